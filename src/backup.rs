@@ -0,0 +1,429 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use russh_sftp::client::SftpSession;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::{
+        config_json, load_config, load_config_remote_in, previous_config, stage_config,
+        stage_config_remote, UpdaterConfig, CONFIG_NAME,
+    },
+    error::{Error, IoContext, Result, SftpContext},
+    log,
+    mrpack::check_pack_path,
+    transaction::{
+        create_parent_dirs_remote, remove_dir_all_remote, RemoteTransaction, Transaction,
+    },
+};
+
+/// Folder inside the work folder that holds one folder per snapshot.
+const BACKUP_FOLDER: &str = ".nmu-backups";
+/// Number of snapshots kept unless configured otherwise.
+pub const DEFAULT_KEEP_BACKUPS: usize = 5;
+
+/// A copy of the managed files and `updater.json` as they were before an update or restore.
+pub struct Snapshot {
+    /// Folder name of the snapshot, the UTC time it was taken.
+    pub(crate) name: String,
+    pub(crate) config: UpdaterConfig,
+}
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: pack {}, {} files",
+            self.name,
+            self.config.pack_version.as_deref().unwrap_or("unknown"),
+            self.config.files.len()
+        )
+    }
+}
+/// Name for a snapshot taken now that `taken` does not report as used yet. Sorts by time.
+fn snapshot_name(taken: impl Fn(&str) -> bool) -> String {
+    let now = OffsetDateTime::now_utc();
+    let name = format!(
+        "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    (1..)
+        .map(|n| match n {
+            1 => name.clone(),
+            n => format!("{name}-{n}"),
+        })
+        .find(|name| !taken(name))
+        .expect("Some snapshot name is free")
+}
+/// Where the snapshot `name` is, relative to the work folder. Rejects names that are not a plain
+/// folder name.
+fn snapshot_folder(name: &str) -> Result<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(Path::new(BACKUP_FOLDER).join(name)),
+        _ => Err(Error::NoBackup(name.to_string())),
+    }
+}
+/// Snapshots `config` and its files, then drops the oldest snapshots beyond `keep`. Does nothing
+/// if there is no `updater.json` yet or `keep` is 0.
+pub fn back_up_local(folder: &Path, config: Option<&UpdaterConfig>, keep: usize) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    if keep == 0 {
+        return Ok(());
+    }
+    take_snapshot_local(folder, config)?;
+    prune_local(folder, keep, None)
+}
+fn take_snapshot_local(folder: &Path, config: &UpdaterConfig) -> Result<()> {
+    let name = snapshot_name(|name| folder.join(BACKUP_FOLDER).join(name).exists());
+    let snapshot = folder.join(snapshot_folder(&name)?);
+    log!(
+        "Backing up {} files to {}",
+        config.files.len(),
+        snapshot.display()
+    );
+    fs::create_dir_all(&snapshot).at(&snapshot)?;
+    for path in config.files.keys() {
+        check_pack_path(path)?;
+        let installed = folder.join(path);
+        if !installed.exists() {
+            log!("{} is missing, not backing it up", path.display());
+            continue;
+        }
+        copy_file(&installed, &snapshot.join(path))?;
+    }
+    let config_path = snapshot.join(CONFIG_NAME);
+    fs::write(&config_path, config_json(config)?).at(&config_path)
+}
+/// The same as [`back_up_local`], for a work folder reached over SFTP.
+pub async fn back_up_remote(
+    sftp: &SftpSession,
+    config: Option<&UpdaterConfig>,
+    keep: usize,
+) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    if keep == 0 {
+        return Ok(());
+    }
+    take_snapshot_remote(sftp, config).await?;
+    prune_remote(sftp, keep, None).await
+}
+async fn take_snapshot_remote(sftp: &SftpSession, config: &UpdaterConfig) -> Result<()> {
+    let taken = snapshot_names_remote(sftp).await?;
+    let name = snapshot_name(|name| taken.iter().any(|taken| taken == name));
+    let snapshot = snapshot_folder(&name)?;
+    log!(
+        "Backing up {} files to {}",
+        config.files.len(),
+        snapshot.display()
+    );
+    for path in config.files.keys() {
+        check_pack_path(path)?;
+        let installed = path.to_string_lossy();
+        if !sftp.try_exists(installed.clone()).await.unwrap_or(false) {
+            log!("{} is missing, not backing it up", path.display());
+            continue;
+        }
+        copy_file_remote(sftp, path, &snapshot.join(path)).await?;
+    }
+    let config_path = snapshot.join(CONFIG_NAME);
+    create_parent_dirs_remote(sftp, &config_path).await;
+    let config_path = config_path.to_string_lossy().to_string();
+    let mut file = sftp.create(config_path.clone()).await.at(&config_path)?;
+    file.write_all(config_json(config)?.as_bytes())
+        .await
+        .at(config_path.clone())?;
+    file.shutdown().await.at(config_path)
+}
+/// Snapshots in the work folder, oldest first.
+pub fn snapshots_local(folder: &Path) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for name in snapshot_names_local(folder)? {
+        let config = load_config(&folder.join(snapshot_folder(&name)?))?;
+        snapshots.push(Snapshot { name, config });
+    }
+    Ok(snapshots)
+}
+/// The same as [`snapshots_local`], for a work folder reached over SFTP.
+pub async fn snapshots_remote(sftp: &SftpSession) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for name in snapshot_names_remote(sftp).await? {
+        let config = load_config_remote_in(sftp, &snapshot_folder(&name)?).await?;
+        snapshots.push(Snapshot { name, config });
+    }
+    Ok(snapshots)
+}
+fn snapshot_names_local(folder: &Path) -> Result<Vec<String>> {
+    let backups = folder.join(BACKUP_FOLDER);
+    if !backups.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&backups).at(&backups)? {
+        let entry = entry.at(&backups)?;
+        if entry.file_type().at(entry.path())?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+async fn snapshot_names_remote(sftp: &SftpSession) -> Result<Vec<String>> {
+    if !sftp.try_exists(BACKUP_FOLDER).await.unwrap_or(false) {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in sftp.read_dir(BACKUP_FOLDER).await.at(BACKUP_FOLDER)? {
+        let name = entry.file_name();
+        if name != "." && name != ".." && entry.file_type().is_dir() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+/// Removes the oldest snapshots until at most `keep` are left, besides `except`.
+fn prune_local(folder: &Path, keep: usize, except: Option<&str>) -> Result<()> {
+    let names = prunable(snapshot_names_local(folder)?, keep, except);
+    for name in &names {
+        let snapshot = folder.join(snapshot_folder(name)?);
+        log!("Removing old backup {name}");
+        fs::remove_dir_all(&snapshot).at(snapshot)?;
+    }
+    Ok(())
+}
+async fn prune_remote(sftp: &SftpSession, keep: usize, except: Option<&str>) -> Result<()> {
+    let names = prunable(snapshot_names_remote(sftp).await?, keep, except);
+    for name in &names {
+        log!("Removing old backup {name}");
+        remove_dir_all_remote(sftp, snapshot_folder(name)?.to_string_lossy().to_string()).await?;
+    }
+    Ok(())
+}
+/// Oldest of `names` beyond the newest `keep`, leaving out `except`.
+fn prunable(mut names: Vec<String>, keep: usize, except: Option<&str>) -> Vec<String> {
+    names.retain(|name| Some(name.as_str()) != except);
+    names.truncate(names.len().saturating_sub(keep));
+    names
+}
+/// Puts the files and `updater.json` of the snapshot `name` back in place of the installed ones,
+/// or of the newest snapshot if `name` is `None`. The current files are backed up first, so a
+/// restore can be undone the same way.
+pub fn restore_local(folder: &Path, name: Option<&str>, keep: usize) -> Result<Snapshot> {
+    let snapshot = pick_snapshot(snapshots_local(folder)?, name)?;
+    let snapshot_dir = folder.join(snapshot_folder(&snapshot.name)?);
+    let current = previous_config(load_config(folder))?;
+    // Taken after the snapshot is picked, so restoring the newest one does not pick this backup.
+    // Pruned only once the restore is committed and never the restored snapshot.
+    if let Some(current) = &current {
+        take_snapshot_local(folder, current)?;
+    }
+    log!("Restoring {snapshot}");
+    let mut transaction = Transaction::begin(folder)?;
+    let staged = (|| {
+        for path in stale_files(current.as_ref(), &snapshot.config) {
+            transaction.remove(path);
+        }
+        for path in snapshot.config.files.keys() {
+            check_pack_path(path)?;
+            let backed_up = snapshot_dir.join(path);
+            if !backed_up.exists() {
+                log!("{} is not in the backup, leaving it out", path.display());
+                continue;
+            }
+            copy_file(&backed_up, &transaction.staging_path(path)?)?;
+            transaction.mark_staged(path);
+        }
+        stage_config(&mut transaction, &snapshot.config)
+    })();
+    match staged {
+        Ok(_) => {
+            transaction.commit()?;
+            prune_local(folder, keep.max(1), Some(&snapshot.name))?;
+            Ok(snapshot)
+        }
+        Err(err) => {
+            transaction.abort();
+            Err(err)
+        }
+    }
+}
+/// The same as [`restore_local`], for a work folder reached over SFTP.
+pub async fn restore_remote(
+    sftp: &mut SftpSession,
+    name: Option<&str>,
+    keep: usize,
+) -> Result<Snapshot> {
+    let snapshot = pick_snapshot(snapshots_remote(sftp).await?, name)?;
+    let snapshot_dir = snapshot_folder(&snapshot.name)?;
+    let current = previous_config(load_config_remote_in(sftp, Path::new("")).await)?;
+    if let Some(current) = &current {
+        take_snapshot_remote(sftp, current).await?;
+    }
+    log!("Restoring {snapshot}");
+    let mut transaction = RemoteTransaction::begin(sftp).await?;
+    let staged = async {
+        for path in stale_files(current.as_ref(), &snapshot.config) {
+            transaction.remove(path);
+        }
+        for path in snapshot.config.files.keys() {
+            check_pack_path(path)?;
+            let backed_up = snapshot_dir.join(path);
+            if !sftp
+                .try_exists(backed_up.to_string_lossy())
+                .await
+                .unwrap_or(false)
+            {
+                log!("{} is not in the backup, leaving it out", path.display());
+                continue;
+            }
+            let staged_path = transaction.staging_path(sftp, path).await;
+            copy_file_remote(sftp, &backed_up, Path::new(&staged_path)).await?;
+            transaction.mark_staged(path);
+        }
+        stage_config_remote(sftp, &mut transaction, &snapshot.config).await
+    }
+    .await;
+    match staged {
+        Ok(_) => {
+            transaction.commit(sftp).await?;
+            prune_remote(sftp, keep.max(1), Some(&snapshot.name)).await?;
+            Ok(snapshot)
+        }
+        Err(err) => {
+            transaction.abort(sftp).await;
+            Err(err)
+        }
+    }
+}
+/// The snapshot called `name`, or the newest one.
+fn pick_snapshot(mut snapshots: Vec<Snapshot>, name: Option<&str>) -> Result<Snapshot> {
+    match name {
+        Some(name) => snapshots
+            .into_iter()
+            .find(|snapshot| snapshot.name == name)
+            .ok_or_else(|| Error::NoBackup(name.to_string())),
+        None => snapshots.pop().ok_or(Error::NoBackups),
+    }
+}
+/// Managed files that are not part of the restored snapshot, except protected ones.
+fn stale_files<'a>(
+    current: Option<&'a UpdaterConfig>,
+    restored: &'a UpdaterConfig,
+) -> impl Iterator<Item = &'a PathBuf> {
+    current
+        .into_iter()
+        .flat_map(|current| current.files.keys())
+        .filter(|path| !restored.files.contains_key(*path) && !restored.protected.contains(path))
+}
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).at(parent)?;
+    }
+    fs::copy(from, to).at(to)?;
+    Ok(())
+}
+async fn copy_file_remote(sftp: &SftpSession, from: &Path, to: &Path) -> Result<()> {
+    create_parent_dirs_remote(sftp, to).await;
+    let (from, to) = (
+        from.to_string_lossy().to_string(),
+        to.to_string_lossy().to_string(),
+    );
+    let mut source = sftp.open(from.clone()).await.at(&from)?;
+    let mut copy = sftp.create(to.clone()).await.at(&to)?;
+    tokio::io::copy(&mut source, &mut copy)
+        .await
+        .at(to.clone())?;
+    copy.shutdown().await.at(to)
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::FileRecord;
+
+    /// Writes `files` with their contents and an `updater.json` managing them.
+    fn install(folder: &Path, files: &[(&str, &str)]) -> UpdaterConfig {
+        let mut records = BTreeMap::new();
+        for (path, contents) in files {
+            let installed = folder.join(path);
+            fs::create_dir_all(installed.parent().unwrap()).unwrap();
+            fs::write(&installed, contents).unwrap();
+            records.insert(PathBuf::from(path), FileRecord::default());
+        }
+        let config = UpdaterConfig::with_files(records);
+        fs::write(folder.join(CONFIG_NAME), config_json(&config).unwrap()).unwrap();
+        config
+    }
+    fn read(folder: &Path, path: &str) -> String {
+        fs::read_to_string(folder.join(path)).unwrap()
+    }
+
+    #[test]
+    fn restores_a_backup_when_only_one_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path();
+        let first = install(folder, &[("mods/a.jar", "a1")]);
+        back_up_local(folder, Some(&first), 1).unwrap();
+        install(folder, &[("mods/a.jar", "a2"), ("mods/b.jar", "b2")]);
+
+        let restored = restore_local(folder, None, 1).unwrap();
+        assert_eq!(read(folder, "mods/a.jar"), "a1");
+        assert!(!folder.join("mods/b.jar").exists());
+        let config = load_config(folder).unwrap();
+        assert_eq!(
+            config.files.keys().collect::<Vec<_>>(),
+            [Path::new("mods/a.jar")]
+        );
+        // The state before the restore was backed up, and the restored snapshot is still there.
+        let names = snapshot_names_local(folder).unwrap();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&restored.name));
+
+        restore_local(folder, None, 1).unwrap();
+        assert_eq!(read(folder, "mods/a.jar"), "a2");
+        assert_eq!(read(folder, "mods/b.jar"), "b2");
+    }
+
+    #[test]
+    fn restores_the_oldest_backup_when_all_slots_are_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path();
+        for version in 1..=3 {
+            let config = install(folder, &[("mods/a.jar", &format!("a{version}"))]);
+            back_up_local(folder, Some(&config), 3).unwrap();
+        }
+        let oldest = snapshot_names_local(folder).unwrap().remove(0);
+        restore_local(folder, Some(&oldest), 3).unwrap();
+        assert_eq!(read(folder, "mods/a.jar"), "a1");
+    }
+
+    #[test]
+    fn refuses_to_back_up_paths_outside_the_work_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("server");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let config = UpdaterConfig::with_files(BTreeMap::from([(
+            PathBuf::from("../secret.txt"),
+            FileRecord::default(),
+        )]));
+        assert!(matches!(
+            back_up_local(&folder, Some(&config), 1),
+            Err(Error::UnsafePath { .. })
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{env, fmt::Display, fs, path::PathBuf, time::SystemTime};
+
+use tempfile::TempPath;
+
+use crate::error::{IoContext, Result};
+
+/// Default limit for the total size of the cache, in bytes.
+pub const DEFAULT_CACHE_SIZE: u64 = 2_000_000_000;
+/// Folder inside the cache for downloads that are not verified yet.
+const TEMP_FOLDER: &str = "tmp";
+
+/// Content-addressed store of downloaded pack files, keyed by their sha512. Shared by every update
+/// run from this machine, so a mod used by several servers is only fetched once.
+#[derive(Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    /// Total size in bytes that [`Cache::prune`] trims the cache down to.
+    max_size: u64,
+}
+impl Cache {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+    /// A cache in [the default folder](Cache::default_dir) with the default size limit.
+    pub fn at_default_dir() -> Option<Self> {
+        Self::default_dir().map(|dir| Self::new(dir, DEFAULT_CACHE_SIZE))
+    }
+    /// `NMU_CACHE_DIR` if set, otherwise `nmu` in the platform's cache folder.
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("NMU_CACHE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+        #[cfg(windows)]
+        let base = env::var_os("LOCALAPPDATA").map(PathBuf::from);
+        #[cfg(not(windows))]
+        let base = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")));
+        base.map(|base| base.join("nmu"))
+    }
+    /// Where a file with this sha512 is stored. `None` for anything that is not a sha512, so a
+    /// hostile pack index can not point outside the cache.
+    fn path(&self, sha512: &str) -> Option<PathBuf> {
+        if sha512.len() != 128 || !sha512.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let sha512 = sha512.to_ascii_lowercase();
+        Some(self.dir.join(&sha512[..2]).join(sha512))
+    }
+    /// Returns the cached file with this sha512, marking it as recently used.
+    pub fn get(&self, sha512: &str) -> Option<PathBuf> {
+        let path = self.path(sha512)?;
+        let file = fs::File::options().append(true).open(&path).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+        Some(path)
+    }
+    /// A fresh file to download into before it is [inserted](Cache::insert).
+    pub fn temp_file(&self) -> Result<TempPath> {
+        let temp = self.dir.join(TEMP_FOLDER);
+        fs::create_dir_all(&temp).at(&temp)?;
+        Ok(tempfile::NamedTempFile::new_in(&temp)
+            .at(&temp)?
+            .into_temp_path())
+    }
+    /// Moves a verified download into the cache. Returns `None` if the hash can not be used as a key.
+    pub fn insert(&self, file: TempPath, sha512: &str) -> Result<Option<PathBuf>> {
+        let Some(path) = self.path(sha512) else {
+            return Ok(None);
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).at(parent)?;
+        }
+        file.persist(&path).map_err(|err| err.error).at(&path)?;
+        Ok(Some(path))
+    }
+    /// Drops a cached file, for example because it no longer matches its hash.
+    pub fn evict(&self, sha512: &str) {
+        if let Some(path) = self.path(sha512) {
+            let _ = fs::remove_file(path);
+        }
+    }
+    /// Removes the least recently used files until the cache fits in its size limit.
+    pub fn prune(&self) -> Result<PruneResult> {
+        let mut files = Vec::new();
+        if self.dir.exists() {
+            for shard in fs::read_dir(&self.dir).at(&self.dir)? {
+                let shard = shard.at(&self.dir)?;
+                if !shard.file_type().at(shard.path())?.is_dir() || shard.file_name() == TEMP_FOLDER
+                {
+                    continue;
+                }
+                for file in fs::read_dir(shard.path()).at(shard.path())? {
+                    let file = file.at(shard.path())?;
+                    let metadata = file.metadata().at(file.path())?;
+                    let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((used, metadata.len(), file.path()));
+                }
+            }
+        }
+        let mut result = PruneResult {
+            removed: 0,
+            freed: 0,
+            remaining: files.iter().map(|(_, size, _)| size).sum(),
+        };
+        files.sort();
+        for (_, size, path) in files {
+            if result.remaining <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path).at(&path)?;
+            result.removed += 1;
+            result.freed += size;
+            result.remaining -= size;
+        }
+        Ok(result)
+    }
+}
+/// What [`Cache::prune`] removed.
+pub struct PruneResult {
+    pub(crate) removed: usize,
+    /// Bytes freed.
+    pub(crate) freed: u64,
+    /// Bytes still in the cache.
+    pub(crate) remaining: u64,
+}
+impl Display for PruneResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed {} cached files ({:.1} MB), {:.1} MB left",
+            self.removed,
+            self.freed as f64 / 1_000_000.0,
+            self.remaining as f64 / 1_000_000.0
+        )
+    }
+}
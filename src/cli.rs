@@ -0,0 +1,674 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    backup::{restore_local, snapshots_local, DEFAULT_KEEP_BACKUPS},
+    cache::{Cache, DEFAULT_CACHE_SIZE},
+    config::{load_config, UpdaterConfig},
+    diff::PackDiff,
+    error::{runtime, Error, Result},
+    fleet::{run_fleet, FleetJob, Target},
+    ftp::{
+        check_over_sftp, generate_over_sftp, key_needs_passphrase, load_config_over_sftp,
+        restore_over_sftp, snapshots_over_sftp,
+    },
+    generate::generate_at,
+    local::check_local,
+    mrpack::read_pack_info,
+    profile::{Profile, ProfileStore, ProfileTarget},
+    progress::{Cancel, DownloadProgress, Progress, ProgressEvent},
+    secrets::{self, SecretStore},
+    AuthMethod, CollisionPolicy, FtpLocation, OptionalPolicy, PackSource, RunOptions, Side,
+    DEFAULT_CONCURRENCY,
+};
+
+/// Exit codes returned by the command line interface.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+/// Returned by `diff` when the installed files differ from the pack and by `check` when a newer
+/// pack is available.
+pub const EXIT_CHANGES: i32 = 3;
+
+#[derive(Parser)]
+#[command(name = "nmu", about = "Nexusrealms modpack updater", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+#[derive(Subcommand)]
+enum Command {
+    /// Install or update the pack in the target
+    Update {
+        #[command(flatten)]
+        target: TargetArgs,
+        #[command(flatten)]
+        pack: PackArgs,
+        /// Only show what the update would change, like `diff`
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Write an updater.json listing the mods currently in the target
+    Generate {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Show what the target's updater.json records as installed
+    Status {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Show the name, version and game versions of a pack without installing it
+    Info {
+        /// Path to a .mrpack file or URL
+        source: String,
+    },
+    /// Show which files an update would add, replace or remove, and which files not managed by
+    /// the updater it would overwrite
+    Diff {
+        #[command(flatten)]
+        target: TargetArgs,
+        #[command(flatten)]
+        pack: PackArgs,
+    },
+    /// Manage the download cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// List or restore the snapshots taken before each update
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+    /// Manage saved profiles
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommand,
+    },
+    /// Check whether a newer pack is available without installing it
+    Check {
+        #[command(flatten)]
+        target: TargetArgs,
+        /// Path to a .mrpack file or URL to check, defaults to the endpoint in updater.json
+        #[arg(long)]
+        source: Option<String>,
+    },
+    /// Install or update the pack on several servers in one run
+    Fleet {
+        /// Saved profiles to update
+        #[arg(conflicts_with = "all")]
+        profiles: Vec<String>,
+        /// Update every saved profile
+        #[arg(long)]
+        all: bool,
+        /// Local work folder to update besides the profiles, can be given more than once
+        #[arg(long = "folder", value_name = "FOLDER")]
+        folders: Vec<PathBuf>,
+        /// Number of servers updated at the same time
+        #[arg(long, default_value_t = 1)]
+        parallel: usize,
+        /// Trust the servers' keys and add them to known_hosts if they are not known yet
+        #[arg(long)]
+        accept_new_host_key: bool,
+        #[command(flatten)]
+        pack: PackArgs,
+    },
+}
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove the least recently used files until the cache fits in its size limit
+    Prune {
+        #[command(flatten)]
+        cache: CacheArgs,
+    },
+}
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// List the snapshots in the target, oldest first
+    List {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Put the files of a snapshot back, after taking a snapshot of the current files
+    Restore {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+        /// Snapshot to restore, defaults to the newest one
+        #[arg(value_name = "SNAPSHOT")]
+        snapshot: Option<String>,
+        /// Number of snapshots kept in the target
+        #[arg(long, default_value_t = DEFAULT_KEEP_BACKUPS)]
+        keep_backups: usize,
+    },
+}
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// List the saved profiles
+    List,
+    /// Save a target, pack source and side under a name, replacing any profile with that name
+    Save {
+        #[arg(value_name = "NAME")]
+        profile_name: String,
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+        /// Path to a .mrpack file or URL to download it from
+        #[arg(long)]
+        source: Option<String>,
+        /// Side the pack is installed for
+        #[arg(long, value_enum, default_value_t = Side::Server)]
+        side: Side,
+    },
+    /// Delete a saved profile
+    Remove { name: String },
+}
+#[derive(Args)]
+struct TargetArgs {
+    /// Saved profile to use instead of --folder or --address
+    #[arg(long, conflicts_with_all = ["folder", "address"])]
+    profile: Option<String>,
+    /// Local work folder of the server
+    #[arg(
+        long,
+        conflicts_with = "address",
+        required_unless_present_any = ["address", "profile"]
+    )]
+    folder: Option<PathBuf>,
+    /// Address of the SFTP server
+    #[arg(long)]
+    address: Option<String>,
+    #[arg(long, default_value_t = 22)]
+    port: u32,
+    /// SFTP user name
+    #[arg(long, requires = "address", default_value = "")]
+    name: String,
+    /// How to log in to the SFTP server
+    #[arg(long, value_enum, default_value_t = AuthMethod::Password)]
+    auth: AuthMethod,
+    /// SFTP password, looked up in the saved passwords when using a profile
+    #[arg(long, env = "NMU_PASSWORD", hide_env_values = true, default_value = "")]
+    password: String,
+    /// Private key file for `--auth key-file`
+    #[arg(long, required_if_eq("auth", "key-file"))]
+    key_file: Option<PathBuf>,
+    /// Passphrase of an encrypted key file, asked for on the terminal if needed and not given
+    #[arg(long, env = "NMU_KEY_PASSPHRASE", hide_env_values = true)]
+    key_passphrase: Option<String>,
+    /// Trust the server's key and add it to known_hosts if it is not known yet
+    #[arg(long)]
+    accept_new_host_key: bool,
+}
+#[derive(Args)]
+struct PackArgs {
+    /// Path to a .mrpack file or URL to download it from, defaults to the profile's pack source
+    #[arg(long)]
+    source: Option<String>,
+    /// Side the pack is installed for, defaults to the profile's side or server
+    #[arg(long, value_enum)]
+    side: Option<Side>,
+    /// Do not install files marked optional for the chosen side
+    #[arg(long)]
+    skip_optional: bool,
+    /// What to do with files in the way of the pack that the updater did not install
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Skip)]
+    on_collision: CollisionPolicy,
+    /// Maximum number of files downloaded at the same time
+    #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+    jobs: usize,
+    /// Always download, without reading from or adding to the download cache
+    #[arg(long)]
+    no_cache: bool,
+    /// Number of snapshots of the managed files kept in the target, 0 to not back up
+    #[arg(long, default_value_t = DEFAULT_KEEP_BACKUPS)]
+    keep_backups: usize,
+    /// Install or upgrade the server's mod loader to the version the pack depends on. Forge,
+    /// NeoForge and Quilt need Java to run their installer, set NMU_JAVA if it is not on the PATH
+    #[arg(long)]
+    install_loader: bool,
+    /// Apply the pack even if it targets a different Minecraft version than the target runs
+    #[arg(long)]
+    force_minecraft_version: bool,
+    #[command(flatten)]
+    cache: CacheArgs,
+}
+#[derive(Args)]
+struct CacheArgs {
+    /// Folder of the download cache, defaults to NMU_CACHE_DIR or the platform's cache folder
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Size limit of the download cache in MB
+    #[arg(long, default_value_t = DEFAULT_CACHE_SIZE / 1_000_000)]
+    cache_size: u64,
+}
+impl CacheArgs {
+    fn cache(&self) -> Option<Cache> {
+        self.cache_dir
+            .clone()
+            .or_else(Cache::default_dir)
+            .map(|dir| Cache::new(dir, self.cache_size * 1_000_000))
+    }
+}
+impl TargetArgs {
+    /// Flags that select the saved profile `name` and nothing else.
+    fn profile(name: String, accept_new_host_key: bool) -> Self {
+        Self {
+            profile: Some(name),
+            folder: None,
+            address: None,
+            port: 22,
+            name: String::new(),
+            auth: AuthMethod::Password,
+            password: String::new(),
+            key_file: None,
+            key_passphrase: None,
+            accept_new_host_key,
+        }
+    }
+    /// Where to work, from the flags or the saved profile, together with that profile.
+    fn resolve(self) -> Result<(Target, Option<Profile>)> {
+        let profile = match &self.profile {
+            Some(name) => Some(ProfileStore::load()?.get(name)?.clone()),
+            None => None,
+        };
+        let target = self.target_for(profile.as_ref(), &mut None)?;
+        Ok((target, profile))
+    }
+    /// Where to work, from `profile` if given and from the flags otherwise. Saved passwords are
+    /// looked up in `secrets`, which is opened when first needed.
+    fn target_for(
+        self,
+        profile: Option<&Profile>,
+        secrets: &mut Option<SecretStore>,
+    ) -> Result<Target> {
+        let (address, port, name, auth, key_file) = match profile.map(|p| &p.target) {
+            Some(ProfileTarget::Local { folder }) => return Ok(Target::Local(folder.clone())),
+            Some(ProfileTarget::Sftp {
+                address,
+                port,
+                name,
+                auth,
+                key_file,
+            }) => (
+                address.clone(),
+                *port,
+                name.clone(),
+                *auth,
+                key_file.clone(),
+            ),
+            None => match (self.folder, self.address) {
+                (Some(folder), _) => return Ok(Target::Local(folder)),
+                (None, Some(address)) => (address, self.port, self.name, self.auth, self.key_file),
+                (None, None) => unreachable!("clap requires a folder, an address or a profile"),
+            },
+        };
+        let mut key_passphrase = self.key_passphrase;
+        if let (AuthMethod::KeyFile, Some(path), None) = (auth, &key_file, &key_passphrase) {
+            if key_needs_passphrase(path) {
+                key_passphrase = rpassword::prompt_password("Key passphrase: ").ok();
+            }
+        }
+        let mut password = self.password;
+        if profile.is_some() && auth == AuthMethod::Password && password.is_empty() {
+            let secrets = match secrets {
+                Some(secrets) => secrets,
+                None => secrets.insert(open_secret_store()?),
+            };
+            password = secrets
+                .get(&secrets::account(&name, &address, port))?
+                .unwrap_or_default();
+        }
+        let location = FtpLocation {
+            address,
+            port,
+            name,
+            auth,
+            password,
+            key_file,
+            key_passphrase,
+            accept_new_host_key: self.accept_new_host_key,
+        };
+        Ok(Target::Sftp(location))
+    }
+}
+impl PackArgs {
+    fn source(&self, profile: Option<&Profile>) -> PackSource {
+        source_or_profile(self.source.as_deref(), profile)
+    }
+    fn options(&self, profile: Option<&Profile>) -> RunOptions {
+        RunOptions {
+            side: self
+                .side
+                .or(profile.map(|profile| profile.side))
+                .unwrap_or(Side::Server),
+            optional: if self.skip_optional {
+                OptionalPolicy::Skip
+            } else {
+                OptionalPolicy::Install
+            },
+            collisions: self.on_collision,
+            concurrency: self.jobs,
+            progress: Progress::default(),
+            cancel: Cancel::default(),
+            cache: if self.no_cache {
+                None
+            } else {
+                self.cache.cache()
+            },
+            keep_backups: self.keep_backups,
+            install_loader: self.install_loader,
+            force_minecraft_version: self.force_minecraft_version,
+        }
+    }
+}
+/// The OS keyring, or the encrypted password file unlocked with `NMU_MASTER_PASSPHRASE` or a
+/// passphrase typed on the terminal.
+fn open_secret_store() -> Result<SecretStore> {
+    if let Some(store) = SecretStore::without_passphrase() {
+        return Ok(store);
+    }
+    let passphrase = match std::env::var("NMU_MASTER_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            rpassword::prompt_password("Master passphrase: ").map_err(Error::PassphrasePrompt)?
+        }
+    };
+    SecretStore::file(&passphrase)
+}
+fn source_or_profile(source: Option<&str>, profile: Option<&Profile>) -> PackSource {
+    source
+        .or(profile.and_then(|profile| profile.pack_source.as_deref()))
+        .map_or(PackSource::None, PackSource::parse)
+}
+/// Runs the parsed command and returns the process exit code.
+pub fn run(cli: Cli) -> i32 {
+    run_command(cli.command).unwrap_or_else(|err| {
+        eprintln!("Error: {err}");
+        EXIT_FAILURE
+    })
+}
+fn run_command(command: Command) -> Result<i32> {
+    match command {
+        Command::Update {
+            target,
+            pack,
+            dry_run: true,
+        }
+        | Command::Diff { target, pack } => {
+            let (target, profile) = target.resolve()?;
+            let source = pack.source(profile.as_ref());
+            let options = pack.options(profile.as_ref());
+            let diff = target.diff(source, options)?;
+            print_diff(&diff);
+            if diff.is_empty() {
+                Ok(EXIT_OK)
+            } else {
+                Ok(EXIT_CHANGES)
+            }
+        }
+        Command::Update {
+            target,
+            pack,
+            dry_run: false,
+        } => {
+            let (target, profile) = target.resolve()?;
+            let source = pack.source(profile.as_ref());
+            let mut options = pack.options(profile.as_ref());
+            options.progress = print_progress();
+            let diff = target.run(source, options)?;
+            println!("Updated: {diff}");
+            Ok(EXIT_OK)
+        }
+        Command::Generate { target } => {
+            match target.resolve()?.0 {
+                Target::Local(folder) => generate_at(&folder)?,
+                Target::Sftp(location) => generate_over_sftp(location)?,
+            }
+            println!("Generated updater.json");
+            Ok(EXIT_OK)
+        }
+        Command::Status { target } => {
+            let config = match target.resolve()?.0 {
+                Target::Local(folder) => load_config(&folder)?,
+                Target::Sftp(location) => load_config_over_sftp(location)?,
+            };
+            print_status(&config);
+            Ok(EXIT_OK)
+        }
+        Command::Info { source } => {
+            let info = runtime()?.block_on(read_pack_info(&PackSource::parse(&source)))?;
+            println!("{}", info.describe_pack());
+            Ok(EXIT_OK)
+        }
+        Command::Cache {
+            command: CacheCommand::Prune { cache },
+        } => {
+            let result = cache.cache().ok_or(Error::NoCacheDir)?.prune()?;
+            println!("{result}");
+            Ok(EXIT_OK)
+        }
+        Command::Backup {
+            command: BackupCommand::List { target },
+        } => {
+            let snapshots = match target.resolve()?.0 {
+                Target::Local(folder) => snapshots_local(&folder)?,
+                Target::Sftp(location) => snapshots_over_sftp(location)?,
+            };
+            if snapshots.is_empty() {
+                println!("No backups");
+            }
+            for snapshot in snapshots {
+                println!("{snapshot}");
+            }
+            Ok(EXIT_OK)
+        }
+        Command::Backup {
+            command:
+                BackupCommand::Restore {
+                    target,
+                    snapshot,
+                    keep_backups,
+                },
+        } => {
+            let restored = match target.resolve()?.0 {
+                Target::Local(folder) => restore_local(&folder, snapshot.as_deref(), keep_backups)?,
+                Target::Sftp(location) => restore_over_sftp(location, snapshot, keep_backups)?,
+            };
+            println!("Restored {restored}");
+            Ok(EXIT_OK)
+        }
+        Command::Profile { command } => {
+            let mut store = ProfileStore::load()?;
+            match command {
+                ProfileCommand::List => print_profiles(&store),
+                ProfileCommand::Save {
+                    profile_name,
+                    target,
+                    source,
+                    side,
+                } => {
+                    let target = match target.resolve()?.0 {
+                        Target::Local(folder) => ProfileTarget::Local {
+                            folder: std::path::absolute(&folder).unwrap_or(folder),
+                        },
+                        Target::Sftp(location) => {
+                            if location.auth == AuthMethod::Password
+                                && !location.password.is_empty()
+                            {
+                                open_secret_store()?.set(
+                                    &secrets::account(
+                                        &location.name,
+                                        &location.address,
+                                        location.port,
+                                    ),
+                                    &location.password,
+                                )?;
+                            }
+                            ProfileTarget::Sftp {
+                                address: location.address,
+                                port: location.port,
+                                name: location.name,
+                                auth: location.auth,
+                                key_file: location.key_file,
+                            }
+                        }
+                    };
+                    store.upsert(Profile {
+                        name: profile_name,
+                        target,
+                        pack_source: source.map(|source| match PackSource::parse(&source) {
+                            PackSource::FromFile(path) => std::path::absolute(&path)
+                                .unwrap_or(path)
+                                .display()
+                                .to_string(),
+                            _ => source,
+                        }),
+                        side,
+                    });
+                    store.save()?;
+                }
+                ProfileCommand::Remove { name } => {
+                    if let ProfileTarget::Sftp {
+                        address,
+                        port,
+                        name: user,
+                        ..
+                    } = &store.get(&name)?.target
+                    {
+                        open_secret_store()?.delete(&secrets::account(user, address, *port))?;
+                    }
+                    store.remove(&name);
+                    store.save()?;
+                }
+            }
+            Ok(EXIT_OK)
+        }
+        Command::Check { target, source } => {
+            let (target, profile) = target.resolve()?;
+            let source = source_or_profile(source.as_deref(), profile.as_ref());
+            let check = match target {
+                Target::Local(folder) => check_local(&folder, &source)?,
+                Target::Sftp(location) => check_over_sftp(location, source)?,
+            };
+            println!("{check}");
+            if check.update_available {
+                Ok(EXIT_CHANGES)
+            } else {
+                Ok(EXIT_OK)
+            }
+        }
+        Command::Fleet {
+            profiles,
+            all,
+            folders,
+            parallel,
+            accept_new_host_key,
+            pack,
+        } => {
+            let store = ProfileStore::load()?;
+            let profiles = if all {
+                store.profiles.clone()
+            } else {
+                profiles
+                    .iter()
+                    .map(|name| store.get(name).cloned())
+                    .collect::<Result<_>>()?
+            };
+            if profiles.is_empty() && folders.is_empty() {
+                return Err(Error::NoWorkLocation);
+            }
+            // Passwords and key passphrases are all asked for up front, before anything runs.
+            let mut secrets = None;
+            let mut jobs = Vec::new();
+            for profile in profiles {
+                let target = TargetArgs::profile(profile.name.clone(), accept_new_host_key)
+                    .target_for(Some(&profile), &mut secrets)?;
+                jobs.push(FleetJob {
+                    source: pack.source(Some(&profile)),
+                    options: pack.options(Some(&profile)),
+                    name: profile.name,
+                    target,
+                });
+            }
+            for folder in folders {
+                jobs.push(FleetJob {
+                    name: folder.display().to_string(),
+                    target: Target::Local(folder),
+                    source: pack.source(None),
+                    options: pack.options(None),
+                });
+            }
+            let summary = run_fleet(jobs, parallel);
+            println!("{summary}");
+            if summary.failed() > 0 {
+                Ok(EXIT_FAILURE)
+            } else {
+                Ok(EXIT_OK)
+            }
+        }
+    }
+}
+/// Prints a line for every pack file that is ready.
+fn print_progress() -> Progress {
+    let progress = Mutex::new(DownloadProgress::default());
+    Progress::new(move |event| {
+        let mut progress = progress.lock().unwrap();
+        let finished = match &event {
+            ProgressEvent::Finished { path } => Some(path.clone()),
+            _ => None,
+        };
+        progress.apply(event);
+        if let Some(path) = finished {
+            println!(
+                "[{}/{}] {}",
+                progress.files_done,
+                progress.files,
+                path.display()
+            );
+        }
+    })
+}
+fn print_profiles(store: &ProfileStore) {
+    if store.profiles.is_empty() {
+        println!("No saved profiles");
+    }
+    for profile in &store.profiles {
+        let target = match &profile.target {
+            ProfileTarget::Local { folder } => folder.display().to_string(),
+            ProfileTarget::Sftp {
+                address,
+                port,
+                name,
+                ..
+            } => format!("sftp://{name}@{address}:{port}"),
+        };
+        println!(
+            "{}: {target}, pack {}, {:?} side",
+            profile.name,
+            profile.pack_source.as_deref().unwrap_or("not set"),
+            profile.side
+        );
+    }
+}
+fn print_status(config: &UpdaterConfig) {
+    println!("{}", config.describe_pack());
+    if let Some(minecraft) = &config.server_minecraft_version {
+        println!("Server runs Minecraft {minecraft}");
+    }
+    if let Some(loader) = &config.installed_loader {
+        println!("Installed loader: {loader}");
+    }
+    println!(
+        "Pack endpoint: {}",
+        config.pack_endpoint.as_deref().unwrap_or("none")
+    );
+    println!("Managed files: {}", config.files.len());
+    for file in config.files.keys() {
+        println!("  {}", file.display());
+    }
+}
+fn print_diff(diff: &PackDiff) {
+    for line in diff.plan() {
+        println!("{line}");
+    }
+    println!("{diff}");
+}
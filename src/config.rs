@@ -1,84 +1,304 @@
 use std::{
-    fs::{self, File},
-    io::Write,
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    io::ErrorKind,
     path::{Path, PathBuf},
 };
-const CONFIG_NAME: &str = "updater.json";
-use russh_sftp::client::SftpSession;
+pub const CONFIG_NAME: &str = "updater.json";
+/// Layout version of the `updater.json` files written by this version of the updater.
+pub const SCHEMA_VERSION: u32 = 2;
+use russh_sftp::{client::SftpSession, protocol::StatusCode};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::AsyncReadExt;
 
-pub fn load_config(path: &Path) -> Result<UpdaterConfig, &str> {
-    let file = fs::read_to_string(path.join(Path::new(CONFIG_NAME)));
-    let result: Option<UpdaterConfig> = match file {
-        Ok(string) => serde_json::from_str(string.as_str()).ok(),
-        Err(_e) => None,
+use crate::{
+    error::{Error, IoContext, Result, SftpContext},
+    transaction::{RemoteTransaction, Transaction},
+};
+
+pub fn load_config(path: &Path) -> Result<UpdaterConfig> {
+    let config_path = path.join(Path::new(CONFIG_NAME));
+    let string = match fs::read_to_string(&config_path) {
+        Ok(string) => string,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::NoConfig),
+        Err(e) => return Err(e).at(config_path),
     };
-    result.ok_or("Could not get updater config!")
-}
-pub async fn load_config_remote(sftp: &mut SftpSession) -> Result<UpdaterConfig, &str> {
-    let file = sftp.open("updater.json").await;
-    let result: Option<UpdaterConfig> = match file {
-        Ok(mut file) => {
-            let mut read_buf = String::new();
-            file.read_to_string(&mut read_buf).await.unwrap();
-            serde_json::from_str(read_buf.as_str()).ok()
+    parse_config(config_path, &string)
+}
+pub async fn load_config_remote(sftp: &mut SftpSession) -> Result<UpdaterConfig> {
+    load_config_remote_in(sftp, Path::new("")).await
+}
+/// Loads the `updater.json` in `folder` of the remote work folder.
+pub async fn load_config_remote_in(sftp: &SftpSession, folder: &Path) -> Result<UpdaterConfig> {
+    let config_path = folder.join(CONFIG_NAME).to_string_lossy().to_string();
+    let mut file = match sftp.open(config_path.clone()).await {
+        Ok(file) => file,
+        Err(russh_sftp::client::error::Error::Status(status))
+            if status.status_code == StatusCode::NoSuchFile =>
+        {
+            return Err(Error::NoConfig)
         }
-        Err(_e) => None,
+        Err(e) => return Err(e).at(config_path),
     };
-    result.ok_or("Could not get updater config!")
-}
-pub fn write_config(path: &Path, config: &UpdaterConfig) -> Result<(), &'static str> {
-    if let Ok(mut file) = File::create(path.join(Path::new(CONFIG_NAME))) {
-        if let Ok(content) = serde_json::to_string_pretty(config) {
-            return match file.write_all(content.as_bytes()) {
-                Ok(_) => Ok(()),
-                Err(_) => Err("Could not write JSON to file"),
-            };
-        }
-        return Err("Could not serialize config");
+    let mut read_buf = String::new();
+    file.read_to_string(&mut read_buf)
+        .await
+        .at(config_path.clone())?;
+    parse_config(PathBuf::from(config_path), &read_buf)
+}
+/// Parses an `updater.json` of any schema version, migrating older layouts to the current one.
+fn parse_config(path: PathBuf, json: &str) -> Result<UpdaterConfig> {
+    let json_error = |path: &PathBuf| {
+        let path = path.clone();
+        move |source| Error::Json { path, source }
+    };
+    let mut value: Value = serde_json::from_str(json).map_err(json_error(&path))?;
+    let version = value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(1);
+    if version > u64::from(SCHEMA_VERSION) {
+        return Err(Error::ConfigTooNew { path, version });
+    }
+    if version < 2 {
+        migrate_v1(&mut value);
+    }
+    serde_json::from_value(value).map_err(json_error(&path))
+}
+/// Version 1 listed `files` as an array, with their hashes in a separate `hashes` map.
+fn migrate_v1(value: &mut Value) {
+    let Some(config) = value.as_object_mut() else {
+        return;
+    };
+    let hashes = match config.remove("hashes") {
+        Some(Value::Object(hashes)) => hashes,
+        _ => Default::default(),
+    };
+    let files = match config.remove("files") {
+        Some(Value::Array(files)) => files,
+        _ => Vec::new(),
+    };
+    let files: serde_json::Map<String, Value> = files
+        .into_iter()
+        .filter_map(|path| {
+            let path = path.as_str()?.to_string();
+            let sha512 = hashes.get(&path).cloned().unwrap_or(Value::Null);
+            Some((path, serde_json::json!({ "sha512": sha512 })))
+        })
+        .collect();
+    config.insert("files".to_string(), Value::Object(files));
+    config.insert("schema_version".to_string(), Value::from(2));
+}
+/// Treats a missing `updater.json` as a fresh install instead of an error.
+pub fn previous_config(loaded: Result<UpdaterConfig>) -> Result<Option<UpdaterConfig>> {
+    match loaded {
+        Ok(config) => Ok(Some(config)),
+        Err(Error::NoConfig) => Ok(None),
+        Err(e) => Err(e),
     }
-    Err("Could not create config file")
 }
-pub async fn write_config_remote(
+pub fn config_json(config: &UpdaterConfig) -> Result<String> {
+    serde_json::to_string_pretty(config).map_err(|source| Error::Json {
+        path: PathBuf::from(CONFIG_NAME),
+        source,
+    })
+}
+pub fn stage_config(transaction: &mut Transaction, config: &UpdaterConfig) -> Result<()> {
+    transaction.stage(Path::new(CONFIG_NAME), config_json(config)?.as_bytes())
+}
+pub async fn stage_config_remote(
     sftp: &mut SftpSession,
+    transaction: &mut RemoteTransaction,
     config: &UpdaterConfig,
-) -> Result<(), &'static str> {
-    if let Ok(mut file) = sftp.create("updater.json").await {
-        if let Ok(content) = serde_json::to_string_pretty(config) {
-            return match file.write_all(content.as_bytes()).await {
-                Ok(_) => Ok(()),
-                Err(_) => Err("Could not write JSON to file"),
-            };
-        }
-        return Err("Could not serialize config");
-    }
-    Err("Could not create config file")
+) -> Result<()> {
+    transaction
+        .stage(
+            sftp,
+            Path::new(CONFIG_NAME),
+            config_json(config)?.as_bytes(),
+        )
+        .await
 }
+/// What is installed in a work folder, kept in its `updater.json`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdaterConfig {
-    pub(crate) files: Vec<PathBuf>,
+    pub(crate) schema_version: u32,
+    /// Every file managed by the updater.
+    pub(crate) files: BTreeMap<PathBuf, FileRecord>,
     pub(crate) pack_endpoint: Option<String>,
+    #[serde(default)]
+    pub(crate) pack_name: Option<String>,
+    /// `versionId` of the installed pack.
+    #[serde(default)]
+    pub(crate) pack_version: Option<String>,
+    #[serde(default)]
+    pub(crate) pack_summary: Option<String>,
+    #[serde(default)]
+    pub(crate) minecraft_version: Option<String>,
+    /// Mod loader the pack depends on, such as `fabric-loader` or `neoforge`.
+    #[serde(default)]
+    pub(crate) loader: Option<String>,
+    #[serde(default)]
+    pub(crate) loader_version: Option<String>,
+    /// Minecraft version the server ran at the last update, read from its files.
+    #[serde(default)]
+    pub(crate) server_minecraft_version: Option<String>,
+    /// Server loader the updater installed, none if it was never asked to.
+    #[serde(default)]
+    pub(crate) installed_loader: Option<InstalledLoader>,
+    /// ETag the pack endpoint sent for the installed pack, to check for updates without downloading.
+    #[serde(default)]
+    pub(crate) pack_etag: Option<String>,
+    /// sha512 of the installed pack's `modrinth.index.json`.
+    #[serde(default)]
+    pub(crate) index_hash: Option<String>,
+    /// Paths the updater never writes or deletes, even if the pack contains them. Edited by hand.
+    #[serde(default)]
+    pub(crate) protected: Vec<PathBuf>,
 }
-pub fn delete_by_config(path: &Path, config: &UpdaterConfig) -> Result<(), &'static str> {
-    for file in &config.files {
-        let _ = fs::remove_file(path.join(file));
+impl UpdaterConfig {
+    /// A config that manages `files` and knows nothing about the pack.
+    pub fn with_files(files: BTreeMap<PathBuf, FileRecord>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            files,
+            pack_endpoint: None,
+            pack_name: None,
+            pack_version: None,
+            pack_summary: None,
+            minecraft_version: None,
+            loader: None,
+            loader_version: None,
+            server_minecraft_version: None,
+            installed_loader: None,
+            pack_etag: None,
+            index_hash: None,
+            protected: Vec::new(),
+        }
     }
-    Ok(())
-}
-pub async fn delete_by_config_remote(
-    sftp: &mut SftpSession,
-    config: &UpdaterConfig,
-) -> Result<(), &'static str> {
-    for file in &config.files {
-        match sftp.remove_file(file.to_string_lossy()).await {
-            Ok(_) => {
-                println!("Deleted file {file:?}")
-            }
-            Err(_) => {
-                println!("Failed deleting {file:?}, continuing to delete other files");
+    /// Name, version, summary and game versions of the pack, one per line.
+    pub fn describe_pack(&self) -> String {
+        let mut description = format!(
+            "{} {}",
+            self.pack_name.as_deref().unwrap_or("Unnamed pack"),
+            self.pack_version.as_deref().unwrap_or("(no version)")
+        );
+        if let Some(summary) = &self.pack_summary {
+            description += &format!("\n{summary}");
+        }
+        if let Some(minecraft) = &self.minecraft_version {
+            description += &format!("\nMinecraft {minecraft}");
+            if let (Some(loader), Some(version)) = (&self.loader, &self.loader_version) {
+                description += &format!(", {loader} {version}");
             }
         }
+        description
+    }
+}
+/// What is known about a managed file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileRecord {
+    /// Used to only replace files that changed. Files listed by `generate` have none, so the next
+    /// update replaces them.
+    #[serde(default)]
+    pub(crate) sha512: Option<String>,
+    #[serde(default)]
+    pub(crate) size: Option<u64>,
+    /// URL the file was downloaded from, which is the mirror that served it. None for files from
+    /// the pack's overrides and files first installed from the download cache.
+    #[serde(default)]
+    pub(crate) source: Option<String>,
+    /// When the updater last wrote the file, in RFC 3339.
+    #[serde(default)]
+    pub(crate) installed_at: Option<String>,
+    /// Installed with the server's mod loader rather than from the pack.
+    #[serde(default)]
+    pub(crate) loader: bool,
+}
+/// A mod loader installed in a server work folder.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstalledLoader {
+    /// Name of the loader as a pack dependency, such as `fabric-loader`.
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) minecraft_version: String,
+}
+impl Display for InstalledLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} for Minecraft {}",
+            self.name, self.version, self.minecraft_version
+        )
+    }
+}
+/// The current time in RFC 3339, for [`FileRecord::installed_at`].
+pub fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .expect("The current time can be formatted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_original_layout() {
+        let json = r#"{ "files": ["mods/a.jar", "mods/b.jar"], "pack_endpoint": null }"#;
+        let config = parse_config(PathBuf::from(CONFIG_NAME), json).unwrap();
+        assert_eq!(config.schema_version, SCHEMA_VERSION);
+        assert_eq!(config.files.len(), 2);
+        assert!(config.files.values().all(|file| file.sha512.is_none()));
+    }
+
+    #[test]
+    fn migrates_hashes_into_file_records() {
+        let json = r#"{
+            "files": ["mods/a.jar", "config/x.toml"],
+            "hashes": { "mods/a.jar": "abc" },
+            "pack_endpoint": "https://example.com/pack.mrpack",
+            "pack_version": "1.0",
+            "protected": ["config/x.toml"]
+        }"#;
+        let config = parse_config(PathBuf::from(CONFIG_NAME), json).unwrap();
+        let files = &config.files;
+        assert_eq!(
+            files[Path::new("mods/a.jar")].sha512.as_deref(),
+            Some("abc")
+        );
+        assert_eq!(files[Path::new("config/x.toml")].sha512, None);
+        assert_eq!(config.pack_version.as_deref(), Some("1.0"));
+        assert_eq!(config.protected, [PathBuf::from("config/x.toml")]);
+    }
+
+    #[test]
+    fn round_trips_the_current_layout() {
+        let mut files = BTreeMap::new();
+        files.insert(
+            PathBuf::from("mods/a.jar"),
+            FileRecord {
+                sha512: Some("abc".to_string()),
+                size: Some(3),
+                source: Some("https://example.com/a.jar".to_string()),
+                installed_at: Some(now()),
+                loader: false,
+            },
+        );
+        let json = config_json(&UpdaterConfig::with_files(files)).unwrap();
+        let config = parse_config(PathBuf::from(CONFIG_NAME), &json).unwrap();
+        assert_eq!(config.files[Path::new("mods/a.jar")].size, Some(3));
+    }
+
+    #[test]
+    fn refuses_newer_layouts() {
+        let json = r#"{ "schema_version": 99, "files": {} }"#;
+        assert!(matches!(
+            parse_config(PathBuf::from(CONFIG_NAME), json),
+            Err(Error::ConfigTooNew { version: 99, .. })
+        ));
     }
-    Ok(())
 }
@@ -0,0 +1,119 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::UpdaterConfig, CollisionPolicy};
+
+/// Difference between the files recorded in the previous `updater.json` and the files of the new pack.
+#[derive(Debug, Default)]
+pub struct PackDiff {
+    pub(crate) added: Vec<PathBuf>,
+    pub(crate) changed: Vec<PathBuf>,
+    pub(crate) removed: Vec<PathBuf>,
+    pub(crate) unchanged: Vec<PathBuf>,
+    /// Added files that are already in the work folder without being recorded in `updater.json`,
+    /// most likely put there by hand. Filled in by [`PackDiff::find_collisions`].
+    pub(crate) collisions: Vec<PathBuf>,
+    /// Pack files that are left alone: protected ones and collisions kept by the policy.
+    pub(crate) skipped: Vec<PathBuf>,
+}
+impl PackDiff {
+    /// Files recorded without a hash count as changed, so configs from older versions get a full reinstall.
+    pub fn between(previous: Option<&UpdaterConfig>, new: &BTreeMap<PathBuf, String>) -> Self {
+        let mut diff = PackDiff::default();
+        let Some(previous) = previous else {
+            diff.added = new.keys().cloned().collect();
+            return diff;
+        };
+        for (path, hash) in new {
+            if previous.protected.contains(path) {
+                diff.skipped.push(path.clone());
+            } else if !previous.files.contains_key(path) {
+                diff.added.push(path.clone());
+            } else if previous
+                .files
+                .get(path)
+                .and_then(|file| file.sha512.as_deref())
+                .is_some_and(|old| old.eq_ignore_ascii_case(hash))
+            {
+                diff.unchanged.push(path.clone());
+            } else {
+                diff.changed.push(path.clone());
+            }
+        }
+        for path in previous.files.keys() {
+            if !new.contains_key(path) && !previous.protected.contains(path) {
+                diff.removed.push(path.clone());
+            }
+        }
+        diff
+    }
+    pub fn needs_write(&self, path: &Path) -> bool {
+        !self.unchanged.iter().any(|unchanged| unchanged == path)
+    }
+    /// Records which added files are already in the work folder.
+    pub fn find_collisions(&mut self, exists: impl Fn(&Path) -> bool) {
+        self.collisions = self
+            .added
+            .iter()
+            .filter(|path| exists(path))
+            .cloned()
+            .collect();
+    }
+    /// Moves the collisions the policy keeps from `added` to `skipped`.
+    pub fn apply_collision_policy(&mut self, policy: CollisionPolicy) {
+        if policy == CollisionPolicy::Skip {
+            self.added.retain(|path| !self.collisions.contains(path));
+            self.skipped.extend(self.collisions.iter().cloned());
+        }
+    }
+    /// Whether applying the diff would touch any file.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+    /// One line per file the update would write, delete or leave alone, marked `+` added,
+    /// `~` replaced, `-` removed, `!` for a file that is not managed by the updater but would be
+    /// overwritten or `=` for a pack file that is skipped.
+    pub fn plan(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for path in &self.added {
+            let mark = if self.collisions.contains(path) {
+                '!'
+            } else {
+                '+'
+            };
+            lines.push(format!("{mark} {}", path.display()));
+        }
+        for path in &self.changed {
+            lines.push(format!("~ {}", path.display()));
+        }
+        for path in &self.removed {
+            lines.push(format!("- {}", path.display()));
+        }
+        for path in &self.skipped {
+            lines.push(format!("= {}", path.display()));
+        }
+        lines
+    }
+}
+impl Display for PackDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} changed, {} removed, {} unchanged",
+            self.added.len(),
+            self.changed.len(),
+            self.removed.len(),
+            self.unchanged.len()
+        )?;
+        if !self.collisions.is_empty() {
+            write!(f, ", {} unmanaged files in the way", self.collisions.len())?;
+        }
+        if !self.skipped.is_empty() {
+            write!(f, ", {} skipped", self.skipped.len())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::{io, path::PathBuf};
+
+/// Everything that can go wrong while updating, with the cause and the file involved where known.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No pack source set")]
+    NoPackSource,
+    #[error("No work location set")]
+    NoWorkLocation,
+    #[error("No updater.json found")]
+    NoConfig,
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("No download cache folder, set NMU_CACHE_DIR or pass --cache-dir")]
+    NoCacheDir,
+    #[error("No folder for settings, set NMU_CONFIG_DIR")]
+    NoConfigDir,
+    #[error("No saved profile named {0}")]
+    NoProfile(String),
+    #[error("Keyring error: {0}")]
+    Keyring(keyring::Error),
+    #[error("Wrong master passphrase")]
+    WrongPassphrase,
+    #[error("The master passphrase can not be empty")]
+    EmptyPassphrase,
+    #[error("Could not ask for the master passphrase: {0}")]
+    PassphrasePrompt(io::Error),
+    #[error("Could not encrypt or decrypt saved passwords")]
+    SecretsCrypto,
+    #[error("Saved passwords in {path} are damaged")]
+    SecretsCorrupt { path: PathBuf },
+    #[error("Could not access {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("Could not start async runtime: {0}")]
+    Runtime(io::Error),
+    #[error("Could not download {url}: {source}")]
+    Http { url: String, source: reqwest::Error },
+    #[error("Could not set up HTTP client: {0}")]
+    HttpClient(reqwest::Error),
+    #[error("Could not read mrpack archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Invalid JSON in {path}: {source}")]
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("{path} points outside the work folder, refusing to install the pack")]
+    UnsafePath { path: PathBuf },
+    #[error("{path} was written by a newer version of the updater (schema {version})")]
+    ConfigTooNew { path: PathBuf, version: u64 },
+    #[error("Pack format version {0} is not supported")]
+    UnsupportedFormat(u32),
+    #[error("Pack is for {0}, not Minecraft")]
+    NotMinecraft(String),
+    #[error("Installing {0} is not supported")]
+    UnsupportedLoader(String),
+    #[error("Could not install {loader}: {reason}")]
+    LoaderInstall { loader: String, reason: String },
+    #[error("Not installing {loader}, files not managed by the updater are in the way: {}. Move them or choose another collision policy", list(.paths))]
+    LoaderFilesInTheWay { loader: String, paths: Vec<PathBuf> },
+    #[error("Pack targets Minecraft {pack} but the server runs {server}, refusing to update unless forced")]
+    MinecraftMismatch { pack: String, server: String },
+    #[error("No backup named {0}")]
+    NoBackup(String),
+    #[error("No backups found")]
+    NoBackups,
+    #[error("{path} has no download links")]
+    NoDownloads { path: PathBuf },
+    #[error("{path} does not match the hashes in the pack definition")]
+    HashMismatch { path: PathBuf },
+    #[error("SSH error: {0}")]
+    Ssh(#[from] russh::Error),
+    #[error("SFTP error on {path}: {source}")]
+    Sftp {
+        path: String,
+        source: russh_sftp::client::error::Error,
+    },
+    #[error("Could not load private key {path}: {source}")]
+    Key {
+        path: PathBuf,
+        source: russh::keys::Error,
+    },
+    #[error("No private key file set")]
+    NoKeyFile,
+    #[error("Private key is encrypted and needs a passphrase")]
+    KeyNeedsPassphrase,
+    #[error("SSH agent error: {0}")]
+    Agent(russh::keys::Error),
+    #[error("Server rejected the SSH login")]
+    AuthRejected,
+    #[error("Host key of the server is not trusted yet")]
+    HostKeyUnknown,
+    #[error("Host key of the server changed since it was trusted (known_hosts line {line}), refusing to connect")]
+    HostKeyChanged { line: usize },
+    #[error("Could not use known_hosts: {0}")]
+    KnownHosts(russh::keys::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Adds the path to IO errors from `std::fs` and friends.
+pub trait IoContext<T> {
+    fn at(self, path: impl Into<PathBuf>) -> Result<T>;
+}
+impl<T> IoContext<T> for io::Result<T> {
+    fn at(self, path: impl Into<PathBuf>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.into(),
+            source,
+        })
+    }
+}
+/// Adds the remote path to SFTP errors.
+pub trait SftpContext<T> {
+    fn at(self, path: impl Into<String>) -> Result<T>;
+}
+impl<T> SftpContext<T> for std::result::Result<T, russh_sftp::client::error::Error> {
+    fn at(self, path: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Sftp {
+            path: path.into(),
+            source,
+        })
+    }
+}
+pub fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Runtime::new().map_err(Error::Runtime)
+}
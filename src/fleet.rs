@@ -0,0 +1,106 @@
+use std::{collections::VecDeque, fmt::Display, path::PathBuf, sync::Mutex, thread};
+
+use crate::{
+    diff::PackDiff,
+    error::Result,
+    ftp::{diff_over_sftp, run_over_sftp},
+    local::{diff_local, run_local},
+    log, FtpLocation, PackSource, RunOptions,
+};
+
+/// A server the pack is installed on.
+pub enum Target {
+    Local(PathBuf),
+    Sftp(FtpLocation),
+}
+impl Target {
+    /// Updates the pack in the target and returns what changed.
+    pub fn run(self, source: PackSource, options: RunOptions) -> Result<PackDiff> {
+        match self {
+            Target::Local(folder) => run_local(&folder, &source, &options),
+            Target::Sftp(location) => run_over_sftp(location, source, options),
+        }
+    }
+    /// Computes what [`Target::run`] would change, without writing anything.
+    pub fn diff(self, source: PackSource, options: RunOptions) -> Result<PackDiff> {
+        match self {
+            Target::Local(folder) => diff_local(&folder, &source, &options),
+            Target::Sftp(location) => diff_over_sftp(location, source, options),
+        }
+    }
+}
+/// One server of a fleet update, with the pack it should run.
+pub struct FleetJob {
+    /// Shown in the log and the summary, usually the profile name.
+    pub(crate) name: String,
+    pub(crate) target: Target,
+    pub(crate) source: PackSource,
+    pub(crate) options: RunOptions,
+}
+/// How updating one server of the fleet went.
+pub struct FleetResult {
+    pub(crate) name: String,
+    pub(crate) outcome: Result<PackDiff>,
+}
+/// Updates every server, up to `parallel` at the same time. A failed server does not stop the
+/// others. Results are in the same order as `jobs`.
+pub fn run_fleet(jobs: Vec<FleetJob>, parallel: usize) -> FleetSummary {
+    let workers = parallel.clamp(1, jobs.len().max(1));
+    let queue = Mutex::new(jobs.into_iter().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let Some((index, job)) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+                log!("[{}] Updating", job.name);
+                let outcome = job.target.run(job.source, job.options);
+                match &outcome {
+                    Ok(diff) => log!("[{}] Updated: {diff}", job.name),
+                    Err(err) => log!("[{}] Failed: {err}", job.name),
+                }
+                results.lock().unwrap().push((
+                    index,
+                    FleetResult {
+                        name: job.name,
+                        outcome,
+                    },
+                ));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    FleetSummary {
+        results: results.into_iter().map(|(_, result)| result).collect(),
+    }
+}
+/// Per server results of [`run_fleet`].
+pub struct FleetSummary {
+    pub(crate) results: Vec<FleetResult>,
+}
+impl FleetSummary {
+    pub fn failed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome.is_err())
+            .count()
+    }
+}
+impl Display for FleetSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for result in &self.results {
+            match &result.outcome {
+                Ok(diff) => writeln!(f, "{}: updated, {diff}", result.name)?,
+                Err(err) => writeln!(f, "{}: failed, {err}", result.name)?,
+            }
+        }
+        write!(
+            f,
+            "{} updated, {} failed",
+            self.results.len() - self.failed(),
+            self.failed()
+        )
+    }
+}
@@ -1,23 +1,102 @@
-use std::sync::Arc;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
-    config::{delete_by_config_remote, load_config_remote, write_config_remote},
+    backup::{back_up_remote, restore_remote, snapshots_remote, Snapshot},
+    config::{load_config_remote, previous_config, stage_config_remote, UpdaterConfig},
+    diff::PackDiff,
+    error::{runtime, Error, Result, SftpContext},
     generate::generate_at_remote,
-    mrpack::update_from_mrpack_to_remote,
-    FtpLocation, PackSource,
+    log,
+    minecraft::{minecraft_version_remote, server_minecraft_version},
+    mrpack::{diff_pack, find_collisions_remote, update_from_mrpack_to_remote},
+    transaction::RemoteTransaction,
+    update::{check_for_update, update_source, UpdateCheck},
+    AuthMethod, FtpLocation, PackSource, RunOptions,
+};
+use russh::{
+    client,
+    keys::{
+        agent::client::AgentClient,
+        check_known_hosts,
+        known_hosts::learn_known_hosts,
+        load_secret_key,
+        ssh_key::{self, HashAlg},
+        Error as KeyError, PrivateKeyWithHashAlg,
+    },
+    ChannelId,
 };
-use russh::{client, keys::ssh_key, ChannelId};
 use russh_sftp::client::SftpSession;
-use tokio::runtime::Runtime;
-struct Client;
+/// Result of looking up a server's key in the user's `known_hosts` file.
+#[derive(Clone, Debug)]
+pub enum HostKeyStatus {
+    Known,
+    Unknown(ssh_key::PublicKey),
+    /// A different key is recorded for this host, at the given line of `known_hosts`.
+    Changed(usize),
+}
+pub fn fingerprint(key: &ssh_key::PublicKey) -> String {
+    format!("{} {}", key.algorithm(), key.fingerprint(HashAlg::Sha256))
+}
+fn known_host_status(host: &str, port: u16, key: &ssh_key::PublicKey) -> Result<HostKeyStatus> {
+    match check_known_hosts(host, port, key) {
+        Ok(true) => Ok(HostKeyStatus::Known),
+        Ok(false) => Ok(HostKeyStatus::Unknown(key.clone())),
+        Err(KeyError::KeyChanged { line }) => Ok(HostKeyStatus::Changed(line)),
+        Err(err) => Err(Error::KnownHosts(err)),
+    }
+}
+struct Client {
+    host: String,
+    port: u16,
+    accept_new_host_key: bool,
+    /// What the host key check found, so a failed connection can say why.
+    checked: Arc<Mutex<Option<Result<HostKeyStatus>>>>,
+}
 
 impl client::Handler for Client {
     async fn check_server_key(
         &mut self,
         server_public_key: &ssh_key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        println!("check_server_key: {:?}", server_public_key);
-        Ok(true)
+    ) -> std::result::Result<bool, Self::Error> {
+        let status = known_host_status(&self.host, self.port, server_public_key);
+        let accepted = match &status {
+            Ok(HostKeyStatus::Known) => true,
+            Ok(HostKeyStatus::Unknown(key)) if self.accept_new_host_key => {
+                log!(
+                    "Trusting new host key {} for {}",
+                    fingerprint(key),
+                    self.host
+                );
+                if let Err(err) = learn_known_hosts(&self.host, self.port, key) {
+                    log!("Could not record host key in known_hosts: {err}");
+                }
+                true
+            }
+            Ok(HostKeyStatus::Unknown(key)) => {
+                log!(
+                    "Host key {} of {} is not in known_hosts, refusing to connect",
+                    fingerprint(key),
+                    self.host
+                );
+                false
+            }
+            Ok(HostKeyStatus::Changed(line)) => {
+                log!(
+                    "Host key of {} does not match known_hosts line {line}, refusing to connect",
+                    self.host
+                );
+                false
+            }
+            Err(err) => {
+                log!("{err}");
+                false
+            }
+        };
+        *self.checked.lock().unwrap() = Some(status);
+        Ok(accepted)
     }
 
     async fn data(
@@ -25,87 +104,230 @@ impl client::Handler for Client {
         channel: ChannelId,
         data: &[u8],
         _session: &mut client::Session,
-    ) -> Result<(), Self::Error> {
+    ) -> std::result::Result<(), Self::Error> {
         println!("data on channel {:?}: {}", channel, data.len());
         Ok(())
     }
 
-    type Error = IdkEroor;
-}
-pub fn run_over_sftp(location: FtpLocation, source: PackSource) -> Result<(), &'static str> {
-    let rt = Runtime::new().unwrap();
-    rt.block_on(async move {
-        let config = russh::client::Config::default();
-        let sh = Client {};
-        let mut session = russh::client::connect(
-            Arc::new(config),
+    type Error = Error;
+}
+/// Whether the key file is encrypted and cannot be loaded without a passphrase.
+pub fn key_needs_passphrase(path: &Path) -> bool {
+    matches!(load_secret_key(path, None), Err(KeyError::KeyIsEncrypted))
+}
+async fn authenticate(session: &mut client::Handle<Client>, location: &FtpLocation) -> Result<()> {
+    let success = match location.auth {
+        AuthMethod::Password => session
+            .authenticate_password(location.name.clone(), location.password.clone())
+            .await?
+            .success(),
+        AuthMethod::KeyFile => {
+            let Some(path) = &location.key_file else {
+                return Err(Error::NoKeyFile);
+            };
+            let key = match load_secret_key(path, location.key_passphrase.as_deref()) {
+                Ok(key) => key,
+                Err(KeyError::KeyIsEncrypted) => return Err(Error::KeyNeedsPassphrase),
+                Err(source) => {
+                    return Err(Error::Key {
+                        path: path.clone(),
+                        source,
+                    })
+                }
+            };
+            let hash_alg = session.best_supported_rsa_hash().await?.flatten();
+            session
+                .authenticate_publickey(
+                    location.name.clone(),
+                    PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+                )
+                .await?
+                .success()
+        }
+        AuthMethod::Agent => {
+            #[cfg(unix)]
+            let mut agent = AgentClient::connect_env().await.map_err(Error::Agent)?;
+            #[cfg(windows)]
+            let mut agent = AgentClient::connect_pageant().await;
+            let identities = agent.request_identities().await.map_err(Error::Agent)?;
+            let hash_alg = session.best_supported_rsa_hash().await?.flatten();
+            let mut success = false;
+            for key in identities {
+                if session
+                    .authenticate_publickey_with(location.name.clone(), key, hash_alg, &mut agent)
+                    .await
+                    .is_ok_and(|result| result.success())
+                {
+                    success = true;
+                    break;
+                }
+            }
+            success
+        }
+    };
+    if success {
+        Ok(())
+    } else {
+        Err(Error::AuthRejected)
+    }
+}
+/// Opens an SSH session and starts the SFTP subsystem on it.
+/// The session handle has to be kept alive for as long as the SFTP session is used.
+async fn connect(location: &FtpLocation) -> Result<(client::Handle<Client>, SftpSession)> {
+    let config = russh::client::Config::default();
+    let checked = Arc::new(Mutex::new(None));
+    let sh = Client {
+        host: location.address.clone(),
+        port: location.port as u16,
+        accept_new_host_key: location.accept_new_host_key,
+        checked: checked.clone(),
+    };
+    let mut session = match russh::client::connect(
+        Arc::new(config),
+        (location.address.as_str(), location.port as u16),
+        sh,
+    )
+    .await
+    {
+        Ok(session) => session,
+        Err(err) => {
+            return Err(match checked.lock().unwrap().take() {
+                Some(Ok(HostKeyStatus::Unknown(_))) => Error::HostKeyUnknown,
+                Some(Ok(HostKeyStatus::Changed(line))) => Error::HostKeyChanged { line },
+                Some(Err(err)) => err,
+                _ => err,
+            })
+        }
+    };
+    authenticate(&mut session, location).await?;
+    let channel = session.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    let sftp = SftpSession::new(channel.into_stream()).await.at("/")?;
+    Ok((session, sftp))
+}
+/// Connects only far enough to see the server's key and looks it up in `known_hosts`.
+pub fn check_host_key(location: &FtpLocation) -> Result<HostKeyStatus> {
+    runtime()?.block_on(async move {
+        let checked = Arc::new(Mutex::new(None));
+        let sh = Client {
+            host: location.address.clone(),
+            port: location.port as u16,
+            accept_new_host_key: false,
+            checked: checked.clone(),
+        };
+        let connected = russh::client::connect(
+            Arc::new(russh::client::Config::default()),
             (location.address.as_str(), location.port as u16),
             sh,
         )
-        .await
-        .unwrap();
-        if session
-            .authenticate_password(location.name, location.password)
-            .await
-            .unwrap()
-            .success()
-        {
-            let channel = session.channel_open_session().await.unwrap();
-            channel.request_subsystem(true, "sftp").await.unwrap();
-            let mut sftp = SftpSession::new(channel.into_stream()).await.unwrap();
-            match source {
-                PackSource::None => Err("No pack source set!"),
-                _ => {
-                    if let Ok(config) = load_config_remote(&mut sftp).await {
-                        match delete_by_config_remote(&mut sftp, &config).await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        };
-                    };
-                    match update_from_mrpack_to_remote(&source, &mut sftp).await {
-                        Ok(config) => write_config_remote(&mut sftp, &config).await,
-                        Err(str) => Err(str),
+        .await;
+        let status = checked.lock().unwrap().take();
+        match (status, connected) {
+            (Some(status), _) => status,
+            (None, Err(err)) => Err(err),
+            (None, Ok(_)) => unreachable!("russh always checks the server key before connecting"),
+        }
+    })
+}
+pub fn trust_host_key(location: &FtpLocation, key: &ssh_key::PublicKey) -> Result<()> {
+    learn_known_hosts(&location.address, location.port as u16, key).map_err(Error::KnownHosts)
+}
+/// Updates the pack on the SFTP server and returns what changed.
+pub fn run_over_sftp(
+    location: FtpLocation,
+    source: PackSource,
+    options: RunOptions,
+) -> Result<PackDiff> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        match source {
+            PackSource::None => Err(Error::NoPackSource),
+            _ => {
+                let previous = previous_config(load_config_remote(&mut sftp).await)?;
+                back_up_remote(&sftp, previous.as_ref(), options.keep_backups).await?;
+                let mut transaction = RemoteTransaction::begin(&mut sftp).await?;
+                let staged = match update_from_mrpack_to_remote(
+                    &source,
+                    &mut sftp,
+                    &mut transaction,
+                    &options,
+                    previous.as_ref(),
+                )
+                .await
+                {
+                    Ok((config, diff)) => stage_config_remote(&mut sftp, &mut transaction, &config)
+                        .await
+                        .map(|_| diff),
+                    Err(err) => Err(err),
+                }
+                .and_then(|diff| options.cancel.check().map(|_| diff));
+                match staged {
+                    Ok(diff) => transaction.commit(&mut sftp).await.map(|_| diff),
+                    Err(err) => {
+                        transaction.abort(&mut sftp).await;
+                        Err(err)
                     }
                 }
             }
-        } else {
-            Err("ASYNCWRONG")
         }
     })
 }
-pub fn generate_over_sftp(location: FtpLocation) -> Result<(), &'static str> {
-    let rt = Runtime::new().unwrap();
-    rt.block_on(async move {
-        let config = russh::client::Config::default();
-        let sh = Client {};
-        let mut session = russh::client::connect(
-            Arc::new(config),
-            (location.address.as_str(), location.port as u16),
-            sh,
+pub fn generate_over_sftp(location: FtpLocation) -> Result<()> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        generate_at_remote(&mut sftp).await
+    })
+}
+pub fn snapshots_over_sftp(location: FtpLocation) -> Result<Vec<Snapshot>> {
+    runtime()?.block_on(async move {
+        let (_session, sftp) = connect(&location).await?;
+        snapshots_remote(&sftp).await
+    })
+}
+pub fn restore_over_sftp(
+    location: FtpLocation,
+    name: Option<String>,
+    keep: usize,
+) -> Result<Snapshot> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        restore_remote(&mut sftp, name.as_deref(), keep).await
+    })
+}
+pub fn load_config_over_sftp(location: FtpLocation) -> Result<UpdaterConfig> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        load_config_remote(&mut sftp).await
+    })
+}
+/// Computes what [`run_over_sftp`] would change, without writing anything.
+pub fn diff_over_sftp(
+    location: FtpLocation,
+    source: PackSource,
+    options: RunOptions,
+) -> Result<PackDiff> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        let previous = previous_config(load_config_remote(&mut sftp).await)?;
+        let server_minecraft =
+            server_minecraft_version(minecraft_version_remote(&sftp).await?, previous.as_ref());
+        let mut diff = diff_pack(
+            &source,
+            &options,
+            previous.as_ref(),
+            server_minecraft.as_deref(),
         )
-        .await
-        .unwrap();
-        if session
-            .authenticate_password(location.name, location.password)
-            .await
-            .unwrap()
-            .success()
-        {
-            let channel = session.channel_open_session().await.unwrap();
-            channel.request_subsystem(true, "sftp").await.unwrap();
-            let mut sftp = SftpSession::new(channel.into_stream()).await.unwrap();
-            generate_at_remote(&mut sftp).await
-        } else {
-            Err("Setting up SFTP session did not succeed")
-        }
+        .await?;
+        find_collisions_remote(&sftp, &mut diff).await?;
+        diff.apply_collision_policy(options.collisions);
+        Ok(diff)
     })
 }
-#[derive(Debug)]
-struct IdkEroor;
-impl From<russh::Error> for IdkEroor {
-    fn from(_: russh::Error) -> Self {
-        IdkEroor
-    }
+pub fn check_over_sftp(location: FtpLocation, source: PackSource) -> Result<UpdateCheck> {
+    runtime()?.block_on(async move {
+        let (_session, mut sftp) = connect(&location).await?;
+        let previous = previous_config(load_config_remote(&mut sftp).await)?;
+        let source = update_source(&source, previous.as_ref());
+        check_for_update(&source, previous.as_ref()).await
+    })
 }
@@ -1,74 +1,90 @@
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use russh_sftp::client::SftpSession;
 use tokio::io::AsyncWriteExt;
 
-use crate::{config::UpdaterConfig, ftp, NMUClient};
+use crate::{
+    config::{
+        config_json, load_config, load_config_remote, previous_config, FileRecord, UpdaterConfig,
+        SCHEMA_VERSION,
+    },
+    error::{Error, IoContext, Result, SftpContext},
+    ftp, FtpLocation,
+};
 
-pub fn generate(nmu: &NMUClient) -> Result<(), &str> {
-    if let Some(folder) = &nmu.work_folder {
+/// Records for files found on disk, without hashes so the next update replaces them.
+fn unhashed(files: Vec<PathBuf>) -> BTreeMap<PathBuf, FileRecord> {
+    files
+        .into_iter()
+        .map(|path| (path, FileRecord::default()))
+        .collect()
+}
+/// A config managing `files` that keeps the protected paths, pack identity and loader files of
+/// the existing one. The ETag and index hash are dropped so the next update check does not skip
+/// the pack.
+fn regenerated(files: Vec<PathBuf>, previous: Option<UpdaterConfig>) -> UpdaterConfig {
+    let mut files = unhashed(files);
+    match previous {
+        Some(mut previous) => {
+            previous.files.retain(|_, file| file.loader);
+            files.append(&mut previous.files);
+            UpdaterConfig {
+                schema_version: SCHEMA_VERSION,
+                files,
+                pack_etag: None,
+                index_hash: None,
+                ..previous
+            }
+        }
+        None => UpdaterConfig::with_files(files),
+    }
+}
+pub fn generate(work_folder: Option<&Path>, ftp_location: FtpLocation) -> Result<()> {
+    if let Some(folder) = work_folder {
         return generate_at(folder);
-    } else if !nmu.ftp_location.address.is_empty() {
-        return ftp::generate_over_sftp(nmu.ftp_location.clone());
+    } else if !ftp_location.address.is_empty() {
+        return ftp::generate_over_sftp(ftp_location);
     }
-    Err("No work location set!")
+    Err(Error::NoWorkLocation)
 }
-pub fn generate_at(path: &PathBuf) -> Result<(), &str> {
+pub fn generate_at(path: &Path) -> Result<()> {
     let mod_dir = path.join("mods");
     let mut vec: Vec<PathBuf> = Vec::new();
-    match fs::read_dir(mod_dir) {
-        Ok(files) => {
-            for file_result in files {
-                let path_buf = file_result.expect("Now this is not happening").path();
-                vec.push(
-                    path_buf
-                        .strip_prefix(path)
-                        .expect("Should be unreachable")
-                        .to_path_buf(),
-                );
-            }
-            if let Ok(mut file) = File::create(path.join(PathBuf::from("updater.json"))) {
-                let config = UpdaterConfig {
-                    files: vec,
-                    pack_endpoint: None,
-                };
-                let json: String =
-                    serde_json::to_string_pretty(&config).expect("Malformed struct somehow");
-                file.write_all(json.as_bytes())
-                    .expect("Could create but could not write to a file. Curious.");
-                return Ok(());
-            }
-            Err("Could not create config file")
-        }
-        Err(_) => Err("Could not read mod directory!"),
+    for file_result in fs::read_dir(&mod_dir).at(&mod_dir)? {
+        let path_buf = file_result.at(&mod_dir)?.path();
+        vec.push(
+            path_buf
+                .strip_prefix(path)
+                .expect("Entries of mods/ are inside the work folder")
+                .to_path_buf(),
+        );
     }
+    let config = regenerated(vec, previous_config(load_config(path))?);
+    let config_path = path.join(PathBuf::from("updater.json"));
+    let mut file = File::create(&config_path).at(&config_path)?;
+    file.write_all(config_json(&config)?.as_bytes())
+        .at(&config_path)
 }
-pub async fn generate_at_remote(ftp: &mut SftpSession) -> Result<(), &'static str> {
+pub async fn generate_at_remote(ftp: &mut SftpSession) -> Result<()> {
     let mut vec: Vec<PathBuf> = Vec::new();
-    match ftp.read_dir("mods").await {
-        Ok(files) => {
-            for file_result in files {
-                let path: PathBuf = ["mods", file_result.file_name().as_str()].iter().collect();
-                vec.push(path);
-            }
-            let config = UpdaterConfig {
-                files: vec,
-                pack_endpoint: None,
-            };
-            let json: String =
-                serde_json::to_string_pretty(&config).expect("Malformed struct somehow");
-            ftp.create("updater.json")
-                .await
-                .unwrap()
-                .write_all(json.as_bytes())
-                .await
-                .unwrap();
-            Ok(())
+    for file_result in ftp.read_dir("mods").await.at("mods")? {
+        let name = file_result.file_name();
+        if name == "." || name == ".." {
+            continue;
         }
-        Err(_) => Err("Could not read mod directory!"),
+        let path: PathBuf = ["mods", name.as_str()].iter().collect();
+        vec.push(path);
     }
+    let config = regenerated(vec, previous_config(load_config_remote(ftp).await)?);
+    ftp.create("updater.json")
+        .await
+        .at("updater.json")?
+        .write_all(config_json(&config)?.as_bytes())
+        .await
+        .at("updater.json")
 }
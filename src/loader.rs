@@ -0,0 +1,289 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use russh_sftp::client::SftpSession;
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+use tempfile::TempDir;
+
+use crate::{
+    config::{FileRecord, InstalledLoader, UpdaterConfig},
+    error::{Error, IoContext, Result},
+    log,
+    mrpack::{http_client, Mrpack},
+    transaction::{RemoteTransaction, Transaction},
+    RunOptions, Side,
+};
+
+const FABRIC_META: &str = "https://meta.fabricmc.net/v2/versions";
+const QUILT_META: &str = "https://meta.quiltmc.org/v3/versions";
+const FORGE_MAVEN: &str = "https://maven.minecraftforge.net/net/minecraftforge/forge";
+const NEOFORGE_MAVEN: &str = "https://maven.neoforged.net/releases/net/neoforged";
+/// Name the Fabric server launcher gets in the work folder.
+const FABRIC_LAUNCHER: &str = "fabric-server-launch.jar";
+/// Folder inside the temporary folder that the installers write the server files to.
+const SERVER_FOLDER: &str = "server";
+
+/// Server mod loaders the updater can install.
+#[derive(Clone, Copy, Debug)]
+enum Loader {
+    Fabric,
+    Quilt,
+    Forge,
+    NeoForge,
+}
+impl Loader {
+    /// The loader a pack means by the dependency `name`.
+    fn from_dependency(name: &str) -> Option<Self> {
+        match name {
+            "fabric-loader" => Some(Loader::Fabric),
+            "quilt-loader" => Some(Loader::Quilt),
+            "forge" => Some(Loader::Forge),
+            "neoforge" => Some(Loader::NeoForge),
+            _ => None,
+        }
+    }
+}
+/// The loader `pack` depends on, if the options ask for it and the work folder does not have that
+/// version installed yet.
+pub fn loader_to_install(
+    pack: &Mrpack,
+    previous: Option<&UpdaterConfig>,
+    options: &RunOptions,
+) -> Option<InstalledLoader> {
+    if !options.install_loader {
+        return None;
+    }
+    if options.side != Side::Server {
+        log!("Mod loaders are only installed for servers, skipping");
+        return None;
+    }
+    let (name, version) = pack.loader()?;
+    let wanted = InstalledLoader {
+        name: name.to_string(),
+        version: version.to_string(),
+        minecraft_version: pack.dependencies.get("minecraft")?.clone(),
+    };
+    let installed = previous.and_then(|previous| previous.installed_loader.as_ref());
+    (installed != Some(&wanted)).then_some(wanted)
+}
+/// Server files of a loader, ready to be staged into the work folder.
+pub struct PreparedLoader {
+    folder: TempDir,
+    /// Keyed by the path relative to the work folder.
+    files: BTreeMap<PathBuf, FileRecord>,
+}
+impl PreparedLoader {
+    fn server(&self) -> PathBuf {
+        self.folder.path().join(SERVER_FOLDER)
+    }
+    /// Stages the prepared files at `paths`.
+    pub fn stage_local<'a>(
+        &self,
+        transaction: &mut Transaction,
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> Result<()> {
+        for path in paths {
+            let prepared = self.server().join(path);
+            fs::copy(&prepared, transaction.staging_path(path)?).at(&prepared)?;
+            transaction.mark_staged(path);
+        }
+        Ok(())
+    }
+    pub async fn stage_remote<'a>(
+        &self,
+        sftp: &mut SftpSession,
+        transaction: &mut RemoteTransaction,
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> Result<()> {
+        for path in paths {
+            let prepared = self.server().join(path);
+            let contents = tokio::fs::read(&prepared).await.at(&prepared)?;
+            transaction.stage(sftp, path, &contents).await?;
+        }
+        Ok(())
+    }
+}
+/// Downloads the server files of `loader`. Fabric has a ready made server launcher, the other
+/// loaders' installers are run locally with `java`, or the Java set in `NMU_JAVA`.
+pub async fn prepare_loader(loader: &InstalledLoader) -> Result<PreparedLoader> {
+    let kind = Loader::from_dependency(&loader.name)
+        .ok_or_else(|| Error::UnsupportedLoader(loader.name.clone()))?;
+    let folder = tempfile::tempdir().at(std::env::temp_dir())?;
+    let server = folder.path().join(SERVER_FOLDER);
+    fs::create_dir(&server).at(&server)?;
+    let client = http_client()?;
+    let (minecraft, version) = (&loader.minecraft_version, &loader.version);
+    log!("Installing {loader}");
+    let installer = folder.path().join("installer.jar");
+    let install_server = vec![
+        OsString::from("--installServer"),
+        server.clone().into_os_string(),
+    ];
+    match kind {
+        Loader::Fabric => {
+            let launcher = latest_installer(&client, &format!("{FABRIC_META}/installer")).await?;
+            let url = format!(
+                "{FABRIC_META}/loader/{minecraft}/{version}/{}/server/jar",
+                launcher.version
+            );
+            download(&client, &url, &server.join(FABRIC_LAUNCHER)).await?;
+        }
+        Loader::Quilt => {
+            let latest = latest_installer(&client, &format!("{QUILT_META}/installer")).await?;
+            download(&client, &latest.url, &installer).await?;
+            let mut install_dir = OsString::from("--install-dir=");
+            install_dir.push(&server);
+            let args = ["install", "server", minecraft, version]
+                .map(OsString::from)
+                .into_iter()
+                .chain([install_dir])
+                .collect();
+            run_installer(loader, &installer, folder.path(), args).await?;
+        }
+        Loader::Forge => {
+            let url = format!(
+                "{FORGE_MAVEN}/{minecraft}-{version}/forge-{minecraft}-{version}-installer.jar"
+            );
+            download(&client, &url, &installer).await?;
+            run_installer(loader, &installer, folder.path(), install_server).await?;
+        }
+        Loader::NeoForge => {
+            // NeoForge for 1.20.1 was still published under the Forge name.
+            let url = if minecraft == "1.20.1" {
+                format!("{NEOFORGE_MAVEN}/forge/{minecraft}-{version}/forge-{minecraft}-{version}-installer.jar")
+            } else {
+                format!("{NEOFORGE_MAVEN}/neoforge/{version}/neoforge-{version}-installer.jar")
+            };
+            download(&client, &url, &installer).await?;
+            run_installer(loader, &installer, folder.path(), install_server).await?;
+        }
+    }
+    let mut paths = Vec::new();
+    files_in(&server, Path::new(""), &mut paths)?;
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let prepared = server.join(&path);
+        let contents = fs::read(&prepared).at(&prepared)?;
+        let record = FileRecord {
+            sha512: Some(hex::encode(Sha512::digest(&contents))),
+            size: Some(contents.len() as u64),
+            loader: true,
+            ..FileRecord::default()
+        };
+        files.insert(path, record);
+    }
+    Ok(PreparedLoader { folder, files })
+}
+/// Loader files the work folder should have after the update: those of `prepared` when a loader
+/// is installed, otherwise the ones the last update recorded, so they are kept.
+pub fn loader_files(
+    prepared: Option<&PreparedLoader>,
+    previous: Option<&UpdaterConfig>,
+) -> BTreeMap<PathBuf, FileRecord> {
+    match (prepared, previous) {
+        (Some(prepared), _) => prepared.files.clone(),
+        (None, Some(previous)) => previous
+            .files
+            .iter()
+            .filter(|(_, file)| file.loader && file.sha512.is_some())
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect(),
+        (None, None) => BTreeMap::new(),
+    }
+}
+/// An entry of the Fabric or Quilt meta installer list.
+#[derive(Deserialize)]
+struct InstallerVersion {
+    url: String,
+    version: String,
+    /// Only listed by Fabric.
+    #[serde(default)]
+    stable: Option<bool>,
+}
+/// The newest installer listed at `url` that is not marked unstable.
+async fn latest_installer(client: &reqwest::Client, url: &str) -> Result<InstallerVersion> {
+    let body = get(client, url).await?;
+    let versions: Vec<InstallerVersion> =
+        serde_json::from_slice(&body).map_err(|source| Error::Json {
+            path: PathBuf::from(url),
+            source,
+        })?;
+    versions
+        .into_iter()
+        .find(|installer| installer.stable != Some(false))
+        .ok_or_else(|| Error::NoDownloads {
+            path: PathBuf::from(url),
+        })
+}
+async fn get(client: &reqwest::Client, url: &str) -> Result<bytes::Bytes> {
+    let http = |source| Error::Http {
+        url: url.to_string(),
+        source,
+    };
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(http)?
+        .bytes()
+        .await
+        .map_err(http)
+}
+async fn download(client: &reqwest::Client, url: &str, path: &Path) -> Result<()> {
+    let body = get(client, url).await?;
+    fs::write(path, body).at(path)
+}
+/// Runs an installer jar in `folder`, logging its output if it fails.
+async fn run_installer(
+    loader: &InstalledLoader,
+    installer: &Path,
+    folder: &Path,
+    args: Vec<OsString>,
+) -> Result<()> {
+    let java = std::env::var_os("NMU_JAVA").unwrap_or_else(|| OsString::from("java"));
+    let failed = |reason: String| Error::LoaderInstall {
+        loader: loader.to_string(),
+        reason,
+    };
+    let mut command = Command::new(&java);
+    command
+        .arg("-jar")
+        .arg(installer)
+        .args(args)
+        .current_dir(folder);
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .map_err(|err| failed(err.to_string()))?
+        .map_err(|err| failed(format!("could not run {}: {err}", java.to_string_lossy())))?;
+    if !output.status.success() {
+        for line in String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&output.stderr).lines())
+        {
+            log!("{line}");
+        }
+        return Err(failed(format!("installer exited with {}", output.status)));
+    }
+    Ok(())
+}
+/// Adds every file under `folder.join(relative)` to `files`, relative to `folder`.
+fn files_in(folder: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = folder.join(relative);
+    for entry in fs::read_dir(&dir).at(&dir)? {
+        let entry = entry.at(&dir)?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type().at(entry.path())?.is_dir() {
+            files_in(folder, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
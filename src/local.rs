@@ -1,28 +1,59 @@
 use std::path::Path;
 
 use crate::{
-    config::{delete_by_config, load_config, write_config},
-    mrpack::update_from_mrpack_to_local,
-    PackSource,
+    backup::back_up_local,
+    config::{load_config, previous_config, stage_config},
+    diff::PackDiff,
+    error::{runtime, Error, Result},
+    minecraft::{minecraft_version_local, server_minecraft_version},
+    mrpack::{diff_pack, update_from_mrpack_to_local},
+    transaction::Transaction,
+    update::{check_for_update, update_source, UpdateCheck},
+    PackSource, RunOptions,
 };
 
-pub fn run_local(folder: &Path, source: &PackSource) -> Result<(), &'static str> {
+/// Updates the pack in `folder` and returns what changed.
+pub fn run_local(folder: &Path, source: &PackSource, options: &RunOptions) -> Result<PackDiff> {
     match source {
-        PackSource::None => Err("No pack source set!"),
+        PackSource::None => Err(Error::NoPackSource),
         _ => {
-            let folder_path = folder;
-            if let Ok(config) = load_config(folder_path) {
-                match delete_by_config(folder_path, &config) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        return Err(err);
-                    }
-                };
-            };
-            match update_from_mrpack_to_local(source, folder) {
-                Ok(config) => write_config(folder, &config),
-                Err(str) => Err(str),
+            let previous = previous_config(load_config(folder))?;
+            back_up_local(folder, previous.as_ref(), options.keep_backups)?;
+            let mut transaction = Transaction::begin(folder)?;
+            let staged =
+                update_from_mrpack_to_local(source, &mut transaction, options, previous.as_ref())
+                    .and_then(|(config, diff)| {
+                        stage_config(&mut transaction, &config)?;
+                        Ok(diff)
+                    })
+                    .and_then(|diff| options.cancel.check().map(|_| diff));
+            match staged {
+                Ok(diff) => transaction.commit().map(|_| diff),
+                Err(err) => {
+                    transaction.abort();
+                    Err(err)
+                }
             }
         }
     }
 }
+/// Computes what [`run_local`] would change, without writing anything.
+pub fn diff_local(folder: &Path, source: &PackSource, options: &RunOptions) -> Result<PackDiff> {
+    let previous = previous_config(load_config(folder))?;
+    let server_minecraft =
+        server_minecraft_version(minecraft_version_local(folder)?, previous.as_ref());
+    let mut diff = runtime()?.block_on(diff_pack(
+        source,
+        options,
+        previous.as_ref(),
+        server_minecraft.as_deref(),
+    ))?;
+    diff.find_collisions(|path| folder.join(path).exists());
+    diff.apply_collision_policy(options.collisions);
+    Ok(diff)
+}
+pub fn check_local(folder: &Path, source: &PackSource) -> Result<UpdateCheck> {
+    let previous = previous_config(load_config(folder))?;
+    let source = update_source(source, previous.as_ref());
+    runtime()?.block_on(check_for_update(&source, previous.as_ref()))
+}
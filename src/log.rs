@@ -0,0 +1,22 @@
+use std::sync::Mutex;
+
+type Sink = Box<dyn Fn(&str) + Send>;
+/// Extra destination for log lines besides stdout, set while the window runs a job.
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+pub fn set_sink(sink: Option<Sink>) {
+    *SINK.lock().unwrap() = sink;
+}
+pub fn line(message: &str) {
+    println!("{message}");
+    if let Some(sink) = SINK.lock().unwrap().as_ref() {
+        sink(message);
+    }
+}
+/// Like `println!`, but the line also shows up in the window's log panel.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::line(&format!($($arg)*))
+    };
+}
@@ -1,23 +1,53 @@
+use backup::{restore_local, snapshots_local, Snapshot};
+use clap::Parser;
 use config::load_config;
 //#![windows_subsystem = "windows"]
 use eframe::egui;
 use egui::{IconData, ThemePreference};
-use ftp::run_over_sftp;
+use ftp::{
+    check_host_key, check_over_sftp, diff_over_sftp, fingerprint, key_needs_passphrase,
+    restore_over_sftp, run_over_sftp, snapshots_over_sftp, trust_host_key, HostKeyStatus,
+};
 use generate::generate;
-use local::run_local;
-use std::{fmt::Display, path::PathBuf};
+use local::{check_local, diff_local, run_local};
+use profile::{Profile, ProfileStore, ProfileTarget};
+use progress::DownloadProgress;
+use russh::keys::ssh_key::PublicKey;
+use secrets::SecretStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use worker::{JobEvent, Worker};
+mod backup;
+mod cache;
+mod cli;
 mod config;
+mod diff;
+mod error;
+mod fleet;
 mod ftp;
 mod generate;
+mod loader;
 mod local;
+mod log;
+mod minecraft;
 mod mrpack;
-const _UPDATE_ENDPOINT: &str = "/update";
+mod profile;
+mod progress;
+mod secrets;
+mod transaction;
+mod update;
+mod worker;
 fn main() {
-    dotenvy::dotenv().unwrap();
+    if std::env::args_os().len() > 1 {
+        std::process::exit(cli::run(cli::Cli::parse()));
+    }
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_icon(load_icon())
-            .with_inner_size([640.0, 480.0]),
+            .with_inner_size([640.0, 640.0]),
         ..Default::default()
     };
     eframe::run_native(
@@ -49,7 +79,19 @@ pub struct FtpLocation {
     address: String,
     port: u32,
     name: String,
+    auth: AuthMethod,
     password: String,
+    key_file: Option<PathBuf>,
+    /// Passphrase of an encrypted key file. Asked for when needed and never saved.
+    key_passphrase: Option<String>,
+    /// Trust and record the server's key if it is not in `known_hosts` yet.
+    accept_new_host_key: bool,
+}
+impl FtpLocation {
+    /// Account the password of this location is remembered under.
+    fn account(&self) -> String {
+        secrets::account(&self.name, &self.address, self.port)
+    }
 }
 struct NMUClient {
     work_folder: Option<PathBuf>,
@@ -57,128 +99,787 @@ struct NMUClient {
     pack_endpoint: String,
     last_run_result: String,
     ftp_location: FtpLocation,
+    run_options: RunOptions,
+    /// Key of an unknown server waiting for the user to trust it, and what to do afterwards.
+    pending_host_key: Option<(PublicKey, Action)>,
+    /// Action waiting for the passphrase of an encrypted key file.
+    pending_passphrase: Option<Action>,
+    passphrase_input: String,
+    /// Job running in the background. Controls are disabled until it finishes.
+    worker: Option<Worker<JobOutcome>>,
+    download_progress: DownloadProgress,
+    log_lines: Vec<String>,
+    profiles: ProfileStore,
+    /// Name the current settings are saved under.
+    profile_name: String,
+    /// Where passwords are remembered. `None` until the encrypted file is unlocked, if the OS
+    /// keyring can not be used.
+    secrets: Option<SecretStore>,
+    /// Save the password with the profile.
+    remember_password: bool,
+    /// Password work waiting for the master passphrase of the encrypted secrets file.
+    pending_unlock: Option<SecretAction>,
+    master_passphrase_input: String,
+    /// Snapshot to restore, the newest one if empty.
+    restore_snapshot: String,
+    /// Pack recorded in the work folder's updater.json.
+    installed_pack: Option<String>,
+}
+/// How a background job of the window ended.
+enum JobOutcome {
+    /// Message to show.
+    Message(String),
+    /// The SFTP server's key is not in known_hosts, the action waits for the user to trust it.
+    UnknownHostKey(PublicKey, Action),
+}
+impl From<String> for JobOutcome {
+    fn from(message: String) -> Self {
+        JobOutcome::Message(message)
+    }
+}
+#[derive(Clone, Copy)]
+enum SecretAction {
+    Load,
+    Save,
+}
+#[derive(Clone, Copy)]
+enum Action {
+    Run,
+    /// Shows what `Run` would change without writing anything.
+    Plan,
+    Generate,
+    CheckForUpdates,
+    ListBackups,
+    /// Puts the files of a backup snapshot back.
+    Restore,
+    /// Shows the name, version and game versions of the selected pack.
+    PackInfo,
 }
 impl Default for NMUClient {
     fn default() -> Self {
-        Self {
+        let (profiles, last_run_result) = match ProfileStore::load() {
+            Ok(profiles) => (profiles, String::from("Not ran yet")),
+            Err(err) => (ProfileStore::default(), err.to_string()),
+        };
+        let mut client = Self {
             work_folder: None,
             pack_source: PackSource::None,
             pack_endpoint: String::from(""),
-            last_run_result: String::from("Not ran yet"),
+            last_run_result,
             ftp_location: FtpLocation {
-                address: std::env::var("DEFAULT_ADDRESS").unwrap(),
-                port: std::env::var("DEFAULT_PORT").unwrap().parse().unwrap(),
-                name: std::env::var("DEFAULT_NAME").unwrap(),
-                password: std::env::var("DEFAULT_PASSWORD").unwrap(),
+                address: String::new(),
+                port: 22,
+                name: String::new(),
+                auth: AuthMethod::Password,
+                password: String::new(),
+                key_file: None,
+                key_passphrase: None,
+                accept_new_host_key: false,
             },
+            run_options: RunOptions::default(),
+            pending_host_key: None,
+            pending_passphrase: None,
+            passphrase_input: String::new(),
+            worker: None,
+            download_progress: DownloadProgress::default(),
+            log_lines: Vec::new(),
+            profiles,
+            profile_name: String::new(),
+            secrets: SecretStore::without_passphrase(),
+            remember_password: false,
+            pending_unlock: None,
+            master_passphrase_input: String::new(),
+            restore_snapshot: String::new(),
+            installed_pack: None,
+        };
+        if let Some(profile) = client
+            .profiles
+            .last_used
+            .as_deref()
+            .and_then(|name| client.profiles.get(name).ok())
+            .cloned()
+        {
+            client.apply_profile(&profile);
         }
+        client
     }
 }
 impl eframe::App for NMUClient {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         ctx.set_theme(ThemePreference::Dark);
+        self.poll_worker();
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Nexusrealms modpack updater");
-            if ui.button("Select work folder").clicked() {
-                if let Some(path) = rfd::FileDialog::new().pick_folder() {
-                    self.work_folder = Some(path);
-                    if let Ok(updater_config) =
-                        load_config(self.work_folder.as_ref().unwrap().as_path())
-                    {
-                        if let Some(url) = updater_config.pack_endpoint {
-                            self.pack_endpoint = url.clone();
-                            self.pack_source = PackSource::Url(url);
+            let idle = self.worker.is_none();
+            ui.add_enabled_ui(idle, |ui| {
+                ui.horizontal(|ui| {
+                    let mut selected = None;
+                    egui::ComboBox::from_label("Profile")
+                        .selected_text(&self.profile_name)
+                        .show_ui(ui, |ui| {
+                            for profile in &self.profiles.profiles {
+                                if ui
+                                    .selectable_label(
+                                        profile.name == self.profile_name,
+                                        &profile.name,
+                                    )
+                                    .clicked()
+                                {
+                                    selected = Some(profile.clone());
+                                }
+                            }
+                        });
+                    if let Some(profile) = selected {
+                        self.apply_profile(&profile);
+                        self.profiles.last_used = Some(profile.name);
+                        self.save_profiles();
+                    }
+                    ui.text_edit_singleline(&mut self.profile_name);
+                    if ui.button("Save profile").clicked() {
+                        match self.current_profile() {
+                            Some(profile) => {
+                                self.profiles.last_used = Some(profile.name.clone());
+                                self.profiles.upsert(profile);
+                                self.save_profiles();
+                                self.save_password();
+                            }
+                            None => {
+                                self.last_run_result =
+                                    String::from("Name the profile and pick a work location first")
+                            }
+                        }
+                    }
+                    if ui.button("Delete profile").clicked() {
+                        self.forget_profile_password();
+                        self.profiles.remove(&self.profile_name);
+                        self.save_profiles();
+                    }
+                });
+                ui.separator();
+                if ui.button("Select work folder").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                        self.work_folder = Some(path);
+                        self.installed_pack = None;
+                        if let Ok(updater_config) =
+                            load_config(self.work_folder.as_ref().unwrap().as_path())
+                        {
+                            if updater_config.pack_name.is_some() {
+                                self.installed_pack = Some(updater_config.describe_pack());
+                            }
+                            if let Some(url) = updater_config.pack_endpoint {
+                                self.pack_endpoint = url.clone();
+                                self.pack_source = PackSource::Url(url);
+                            }
                         }
                     }
                 }
-            }
-            if let Some(path) = &self.work_folder {
-                ui.label("Work folder: ");
-                ui.monospace(format!("{}", path.display()));
-            }
-            ui.end_row();
-            ui.horizontal(|ui| {
-                if ui.button("Select pack source").clicked() {
-                    if let Some(path) = rfd::FileDialog::new()
-                        .add_filter("Modrinth Modpack File", &["mrpack"])
-                        .pick_file()
+                if let Some(path) = &self.work_folder {
+                    ui.label("Work folder: ");
+                    ui.monospace(format!("{}", path.display()));
+                }
+                ui.end_row();
+                if let (Some(installed), Some(_)) = (&self.installed_pack, &self.work_folder) {
+                    ui.label("Installed pack: ");
+                    ui.label(installed);
+                    ui.end_row();
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Select pack source").clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("Modrinth Modpack File", &["mrpack"])
+                            .pick_file()
+                        {
+                            self.pack_source = PackSource::FromFile(path);
+                            self.perform(ctx, Action::PackInfo, false);
+                        }
+                    }
+                    let label = ui.label("Pack endpoint: ");
+                    ui.text_edit_singleline(&mut self.pack_endpoint)
+                        .labelled_by(label.id);
+                    if ui.button("Set").clicked() {
+                        self.pack_source = PackSource::Url(self.pack_endpoint.clone())
+                    }
+                    if ui.button("Pack info").clicked() {
+                        self.perform(ctx, Action::PackInfo, false);
+                    }
+                });
+                ui.label("Pack source: ");
+                ui.monospace(format!("{}", &self.pack_source));
+                ui.end_row();
+                ui.horizontal(|ui| {
+                    ui.label("Side: ");
+                    ui.radio_value(&mut self.run_options.side, Side::Server, "Server");
+                    ui.radio_value(&mut self.run_options.side, Side::Client, "Client");
+                    ui.separator();
+                    ui.label("Optional files: ");
+                    ui.radio_value(
+                        &mut self.run_options.optional,
+                        OptionalPolicy::Install,
+                        "Install",
+                    );
+                    ui.radio_value(&mut self.run_options.optional, OptionalPolicy::Skip, "Skip");
+                    ui.separator();
+                    ui.label("Unmanaged files in the way: ").on_hover_text(
+                        "Files the pack would overwrite that the updater did not install",
+                    );
+                    ui.radio_value(
+                        &mut self.run_options.collisions,
+                        CollisionPolicy::Skip,
+                        "Keep",
+                    );
+                    ui.radio_value(
+                        &mut self.run_options.collisions,
+                        CollisionPolicy::Backup,
+                        "Back up",
+                    );
+                    ui.radio_value(
+                        &mut self.run_options.collisions,
+                        CollisionPolicy::Overwrite,
+                        "Overwrite",
+                    );
+                    ui.separator();
+                    let concurrency_label = ui.label("Parallel downloads: ");
+                    ui.add(egui::DragValue::new(&mut self.run_options.concurrency).range(1..=16))
+                        .labelled_by(concurrency_label.id);
+                    ui.add_enabled(
+                        self.run_options.side == Side::Server,
+                        egui::Checkbox::new(&mut self.run_options.install_loader, "Install loader"),
+                    )
+                    .on_hover_text("Install or upgrade the server's mod loader to match the pack");
+                    ui.checkbox(
+                        &mut self.run_options.force_minecraft_version,
+                        "Allow other Minecraft version",
+                    )
+                    .on_hover_text(
+                        "Apply the pack even if the server runs a different Minecraft version",
+                    );
+                    let mut use_cache = self.run_options.cache.is_some();
+                    if ui.checkbox(&mut use_cache, "Download cache").changed() {
+                        self.run_options.cache =
+                            use_cache.then(cache::Cache::at_default_dir).flatten();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Run").clicked() {
+                        self.start(ctx, Action::Run);
+                    }
+                    if ui
+                        .button("Plan")
+                        .on_hover_text("Show what Run would change")
+                        .clicked()
                     {
-                        self.pack_source = PackSource::FromFile(path);
+                        self.start(ctx, Action::Plan);
                     }
+                });
+                if ui.button("Generate").clicked() {
+                    self.start(ctx, Action::Generate);
                 }
-                let label = ui.label("Pack endpoint: ");
-                ui.text_edit_singleline(&mut self.pack_endpoint)
-                    .labelled_by(label.id);
-                if ui.button("Set").clicked() {
-                    self.pack_source = PackSource::Url(self.pack_endpoint.clone())
+                if ui.button("Check for updates").clicked() {
+                    self.start(ctx, Action::CheckForUpdates);
                 }
+                ui.horizontal(|ui| {
+                    ui.label("Backups: ");
+                    if ui.button("List").clicked() {
+                        self.start(ctx, Action::ListBackups);
+                    }
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.restore_snapshot)
+                            .hint_text("Newest snapshot")
+                            .desired_width(180.0),
+                    );
+                    if ui
+                        .button("Restore")
+                        .on_hover_text("Put the files of the snapshot back")
+                        .clicked()
+                    {
+                        self.start(ctx, Action::Restore);
+                    }
+                    ui.separator();
+                    let keep_label = ui.label("Keep: ");
+                    ui.add(egui::DragValue::new(&mut self.run_options.keep_backups).range(0..=50))
+                        .labelled_by(keep_label.id);
+                });
+                ui.separator();
+                ui.group(|ui| {
+                    let address_label = ui.label("Address: ");
+                    ui.text_edit_singleline(&mut self.ftp_location.address)
+                        .labelled_by(address_label.id);
+                    let name_label = ui.label("Name: ");
+                    ui.text_edit_singleline(&mut self.ftp_location.name)
+                        .labelled_by(name_label.id);
+                    ui.horizontal(|ui| {
+                        ui.label("Login with: ");
+                        ui.radio_value(
+                            &mut self.ftp_location.auth,
+                            AuthMethod::Password,
+                            "Password",
+                        );
+                        ui.radio_value(
+                            &mut self.ftp_location.auth,
+                            AuthMethod::KeyFile,
+                            "Key file",
+                        );
+                        ui.radio_value(&mut self.ftp_location.auth, AuthMethod::Agent, "SSH agent");
+                    });
+                    match self.ftp_location.auth {
+                        AuthMethod::Password => {
+                            let password_label = ui.label("Password: ");
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut self.ftp_location.password)
+                                        .password(true),
+                                )
+                                .labelled_by(password_label.id);
+                                ui.checkbox(&mut self.remember_password, "Remember with profile");
+                            });
+                        }
+                        AuthMethod::KeyFile => {
+                            ui.horizontal(|ui| {
+                                if ui.button("Select key file").clicked() {
+                                    if let Some(path) = rfd::FileDialog::new().pick_file() {
+                                        self.ftp_location.key_file = Some(path);
+                                        self.ftp_location.key_passphrase = None;
+                                    }
+                                }
+                                if let Some(path) = &self.ftp_location.key_file {
+                                    ui.monospace(format!("{}", path.display()));
+                                }
+                            });
+                        }
+                        AuthMethod::Agent => {}
+                    }
+                    let port_label = ui.label("Port: ");
+                    ui.add(egui::DragValue::new(&mut self.ftp_location.port).speed(10))
+                        .labelled_by(port_label.id);
+                });
             });
-            ui.label("Pack source: ");
-            ui.monospace(format!("{}", &self.pack_source));
-            ui.end_row();
-            if ui.button("Run").clicked() {
-                self.last_run_result = match run(self) {
-                    Ok(_) => String::from("Ran!"),
-                    Err(s) => String::from(s),
+            ui.label(&self.last_run_result);
+            if !idle || self.download_progress.files > 0 {
+                let progress = &self.download_progress;
+                ui.add(egui::ProgressBar::new(progress.fraction()).text(format!(
+                    "{}/{} files, {:.1}/{:.1} MB",
+                    progress.files_done,
+                    progress.files,
+                    progress.downloaded_bytes as f64 / 1_000_000.0,
+                    progress.total_bytes as f64 / 1_000_000.0
+                )));
+                for (path, (downloaded, size)) in &progress.current {
+                    ui.add(
+                        egui::ProgressBar::new(if *size == 0 {
+                            0.0
+                        } else {
+                            *downloaded as f32 / *size as f32
+                        })
+                        .text(path.display().to_string()),
+                    );
                 }
             }
-            if ui.button("Generate").clicked() {
-                self.last_run_result = match generate(self) {
-                    Ok(_) => String::from("Generated!"),
-                    Err(s) => String::from(s),
+            if let Some(worker) = &self.worker {
+                if worker.is_cancelled() {
+                    ui.label("Cancelling...");
+                } else if ui.button("Cancel").clicked() {
+                    worker.cancel();
                 }
             }
             ui.separator();
-            ui.group(|ui| {
-                let address_label = ui.label("Address: ");
-                ui.text_edit_singleline(&mut self.ftp_location.address)
-                    .labelled_by(address_label.id);
-                let name_label = ui.label("Name: ");
-                ui.text_edit_singleline(&mut self.ftp_location.name)
-                    .labelled_by(name_label.id);
-                let password_label = ui.label("Password: ");
-                ui.text_edit_singleline(&mut self.ftp_location.password)
-                    .labelled_by(password_label.id);
-                let port_label = ui.label("Port: ");
-                ui.add(egui::DragValue::new(&mut self.ftp_location.port).speed(10))
-                    .labelled_by(port_label.id);
-            });
-            ui.label(&self.last_run_result);
+            ui.label("Log");
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for line in &self.log_lines {
+                        ui.monospace(line);
+                    }
+                });
         });
+        if let Some((key, action)) = self.pending_host_key.clone() {
+            egui::Window::new("Unknown host key")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(format!(
+                        "{} is not in your known_hosts file. Its key fingerprint is:",
+                        self.ftp_location.address
+                    ));
+                    ui.monospace(fingerprint(&key));
+                    ui.label("Only trust it if it matches the fingerprint of your server.");
+                    ui.horizontal(|ui| {
+                        if ui.button("Trust and continue").clicked() {
+                            self.pending_host_key = None;
+                            match trust_host_key(&self.ftp_location, &key) {
+                                Ok(_) => self.perform(ctx, action, false),
+                                Err(err) => self.last_run_result = err.to_string(),
+                            }
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.pending_host_key = None;
+                            self.last_run_result = String::from("Host key was not trusted");
+                        }
+                    });
+                });
+        }
+        if let Some(action) = self.pending_unlock {
+            egui::Window::new("Saved passwords")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("No system keyring is available. Enter the master passphrase of the encrypted password file:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.master_passphrase_input)
+                            .password(true),
+                    );
+                    ui.horizontal(|ui| {
+                        if ui.button("Unlock").clicked() {
+                            self.pending_unlock = None;
+                            let passphrase = std::mem::take(&mut self.master_passphrase_input);
+                            match SecretStore::file(&passphrase) {
+                                Ok(store) => {
+                                    self.secrets = Some(store);
+                                    match action {
+                                        SecretAction::Load => self.load_password(),
+                                        SecretAction::Save => self.save_password(),
+                                    }
+                                }
+                                Err(err) => self.last_run_result = err.to_string(),
+                            }
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.pending_unlock = None;
+                            self.master_passphrase_input.clear();
+                        }
+                    });
+                });
+        }
+        if let Some(action) = self.pending_passphrase {
+            egui::Window::new("Key passphrase")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("The private key is encrypted. Enter its passphrase:");
+                    ui.add(egui::TextEdit::singleline(&mut self.passphrase_input).password(true));
+                    ui.horizontal(|ui| {
+                        if ui.button("Unlock").clicked() {
+                            self.pending_passphrase = None;
+                            self.ftp_location.key_passphrase =
+                                Some(std::mem::take(&mut self.passphrase_input));
+                            self.start(ctx, action);
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.pending_passphrase = None;
+                            self.passphrase_input.clear();
+                        }
+                    });
+                });
+        }
     }
 }
-fn _update(_nmu: &NMUClient) -> Result<(), &'static str> {
-    Err("Update checking is not implemented")
-    /*if let PackSource::Url(url) = &nmu.pack_source {
-        if let Ok(response) = reqwest::blocking::get(url.clone() + UPDATE_ENDPOINT) {
-            if let Ok(boolean) = serde_json::from_str(
-                response
-                    .text()
-                    .expect("Api response was not readable as text and im tired of if lets")
-                    .as_str(),
+impl NMUClient {
+    /// Fills in the target, pack source and side saved in a profile.
+    fn apply_profile(&mut self, profile: &Profile) {
+        match &profile.target {
+            ProfileTarget::Local { folder } => self.work_folder = Some(folder.clone()),
+            ProfileTarget::Sftp {
+                address,
+                port,
+                name,
+                auth,
+                key_file,
+            } => {
+                self.work_folder = None;
+                self.ftp_location = FtpLocation {
+                    address: address.clone(),
+                    port: *port,
+                    name: name.clone(),
+                    auth: *auth,
+                    password: String::new(),
+                    key_file: key_file.clone(),
+                    key_passphrase: None,
+                    accept_new_host_key: false,
+                };
+            }
+        }
+        self.pack_source = profile
+            .pack_source
+            .as_deref()
+            .map_or(PackSource::None, PackSource::parse);
+        if let PackSource::Url(url) = &self.pack_source {
+            self.pack_endpoint = url.clone();
+        }
+        self.run_options.side = profile.side;
+        self.profile_name = profile.name.clone();
+        self.load_password();
+    }
+    /// Fills in the password remembered for the SFTP location, if any.
+    fn load_password(&mut self) {
+        if self.work_folder.is_some()
+            || self.ftp_location.auth != AuthMethod::Password
+            || self.ftp_location.address.is_empty()
+        {
+            return;
+        }
+        let Some(secrets) = &self.secrets else {
+            if SecretStore::file_exists() {
+                self.pending_unlock = Some(SecretAction::Load);
+            }
+            return;
+        };
+        match secrets.get(&self.ftp_location.account()) {
+            Ok(Some(password)) => {
+                self.ftp_location.password = password;
+                self.remember_password = true;
+            }
+            Ok(None) => self.remember_password = false,
+            Err(err) => self.last_run_result = err.to_string(),
+        }
+    }
+    fn forget_profile_password(&mut self) {
+        if let (
+            Ok(Profile {
+                target:
+                    ProfileTarget::Sftp {
+                        address,
+                        port,
+                        name,
+                        ..
+                    },
+                ..
+            }),
+            Some(secrets),
+        ) = (self.profiles.get(&self.profile_name), &mut self.secrets)
+        {
+            if let Err(err) = secrets.delete(&secrets::account(name, address, *port)) {
+                self.last_run_result = err.to_string();
+            }
+        }
+    }
+    /// Remembers or forgets the SFTP password, following the checkbox.
+    fn save_password(&mut self) {
+        if self.work_folder.is_some()
+            || self.ftp_location.auth != AuthMethod::Password
+            || self.ftp_location.address.is_empty()
+        {
+            return;
+        }
+        let Some(secrets) = &mut self.secrets else {
+            if self.remember_password || SecretStore::file_exists() {
+                self.pending_unlock = Some(SecretAction::Save);
+            }
+            return;
+        };
+        let account = self.ftp_location.account();
+        let result = if self.remember_password && !self.ftp_location.password.is_empty() {
+            secrets.set(&account, &self.ftp_location.password)
+        } else {
+            secrets.delete(&account)
+        };
+        if let Err(err) = result {
+            self.last_run_result = err.to_string();
+        }
+    }
+    /// The current settings as a profile named after the name field, if there is a work location.
+    fn current_profile(&self) -> Option<Profile> {
+        if self.profile_name.is_empty() {
+            return None;
+        }
+        let target = match &self.work_folder {
+            Some(folder) => ProfileTarget::Local {
+                folder: folder.clone(),
+            },
+            None if !self.ftp_location.address.is_empty() => ProfileTarget::Sftp {
+                address: self.ftp_location.address.clone(),
+                port: self.ftp_location.port,
+                name: self.ftp_location.name.clone(),
+                auth: self.ftp_location.auth,
+                key_file: self.ftp_location.key_file.clone(),
+            },
+            None => return None,
+        };
+        Some(Profile {
+            name: self.profile_name.clone(),
+            target,
+            pack_source: self.pack_source.setting(),
+            side: self.run_options.side,
+        })
+    }
+    fn save_profiles(&mut self) {
+        if let Err(err) = self.profiles.save() {
+            self.last_run_result = err.to_string();
+        }
+    }
+    /// Runs the action, asking for the key passphrase first if an SFTP target needs one. The host
+    /// key is checked on the worker, see [`JobOutcome::UnknownHostKey`].
+    fn start(&mut self, ctx: &egui::Context, action: Action) {
+        let sftp = self.work_folder.is_none() && !self.ftp_location.address.is_empty();
+        if sftp {
+            if let (AuthMethod::KeyFile, Some(path), None) = (
+                self.ftp_location.auth,
+                &self.ftp_location.key_file,
+                &self.ftp_location.key_passphrase,
             ) {
-                if boolean {
-                    run(nmu)
-                } else {
-                    Err("Pack is not updated")
+                if key_needs_passphrase(path) {
+                    self.pending_passphrase = Some(action);
+                    return;
+                }
+            }
+        }
+        self.perform(ctx, action, sftp);
+    }
+    /// Runs the action on a background worker, after checking the SFTP server's key if
+    /// `verify_host_key` is set.
+    fn perform(&mut self, ctx: &egui::Context, action: Action, verify_host_key: bool) {
+        if self.worker.is_some() {
+            return;
+        }
+        let work_folder = self.work_folder.clone();
+        let ftp_location = self.ftp_location.clone();
+        let pack_source = self.pack_source.clone();
+        let mut options = self.run_options.clone();
+        let restore_snapshot =
+            Some(self.restore_snapshot.trim().to_string()).filter(|snapshot| !snapshot.is_empty());
+        self.log_lines.clear();
+        self.download_progress = DownloadProgress::default();
+        self.last_run_result = String::from("Running...");
+        self.worker = Some(Worker::spawn(ctx, move |progress, cancel| {
+            options.progress = progress;
+            options.cancel = cancel;
+            if verify_host_key {
+                match check_host_key(&ftp_location) {
+                    Ok(HostKeyStatus::Known) => {}
+                    Ok(HostKeyStatus::Unknown(key)) => {
+                        return JobOutcome::UnknownHostKey(key, action)
+                    }
+                    Ok(HostKeyStatus::Changed(line)) => {
+                        return error::Error::HostKeyChanged { line }.to_string().into()
+                    }
+                    Err(err) => return err.to_string().into(),
+                }
+            }
+            let work_folder = work_folder.as_deref();
+            match action {
+                Action::Run => run(work_folder, ftp_location, pack_source, options)
+                    .map(|diff| format!("Ran! {diff}")),
+                Action::Plan => plan(work_folder, ftp_location, pack_source, options).map(|diff| {
+                    for line in diff.plan() {
+                        log!("{line}");
+                    }
+                    format!("Plan: {diff}")
+                }),
+                Action::Generate => {
+                    generate(work_folder, ftp_location).map(|_| String::from("Generated!"))
+                }
+                Action::CheckForUpdates => {
+                    check_for_updates(work_folder, ftp_location, pack_source)
+                        .map(|check| check.to_string())
+                }
+                Action::ListBackups => list_backups(work_folder, ftp_location).map(|snapshots| {
+                    for snapshot in &snapshots {
+                        log!("{snapshot}");
+                    }
+                    format!("{} backups", snapshots.len())
+                }),
+                Action::Restore => restore(
+                    work_folder,
+                    ftp_location,
+                    restore_snapshot,
+                    options.keep_backups,
+                )
+                .map(|snapshot| format!("Restored {snapshot}")),
+                Action::PackInfo => pack_info(&pack_source),
+            }
+            .unwrap_or_else(|err| err.to_string())
+            .into()
+        }));
+    }
+    fn poll_worker(&mut self) {
+        let Some(worker) = &self.worker else {
+            return;
+        };
+        let mut finished = false;
+        for event in worker.events() {
+            match event {
+                JobEvent::Log(line) => self.log_lines.push(line),
+                JobEvent::Progress(event) => self.download_progress.apply(event),
+                JobEvent::Finished(JobOutcome::Message(message)) => {
+                    self.last_run_result = message;
+                    finished = true;
+                }
+                JobEvent::Finished(JobOutcome::UnknownHostKey(key, action)) => {
+                    self.last_run_result = String::from("Waiting for the host key to be trusted");
+                    self.pending_host_key = Some((key, action));
+                    finished = true;
                 }
-            } else {
-                Err("Could not deserialize update check response")
             }
-        } else {
-            Err("Could not GET from update endpoint")
         }
-    } else {
-        Err("Pack source does not support update checking")
-    }*/
+        if finished {
+            self.worker = None;
+        }
+    }
+}
+fn check_for_updates(
+    work_folder: Option<&Path>,
+    ftp_location: FtpLocation,
+    pack_source: PackSource,
+) -> error::Result<update::UpdateCheck> {
+    if let Some(folder) = work_folder {
+        return check_local(folder, &pack_source);
+    } else if !ftp_location.address.is_empty() {
+        return check_over_sftp(ftp_location, pack_source);
+    }
+    Err(error::Error::NoWorkLocation)
+}
+fn pack_info(pack_source: &PackSource) -> error::Result<String> {
+    let info = error::runtime()?.block_on(mrpack::read_pack_info(pack_source))?;
+    Ok(info.describe_pack())
 }
-fn run(nmu: &NMUClient) -> Result<(), &'static str> {
-    if let Some(folder) = &nmu.work_folder {
-        return run_local(folder, &nmu.pack_source);
-    } else if !nmu.ftp_location.address.is_empty() {
-        return run_over_sftp(nmu.ftp_location.clone(), nmu.pack_source.clone());
+fn list_backups(
+    work_folder: Option<&Path>,
+    ftp_location: FtpLocation,
+) -> error::Result<Vec<Snapshot>> {
+    if let Some(folder) = work_folder {
+        return snapshots_local(folder);
+    } else if !ftp_location.address.is_empty() {
+        return snapshots_over_sftp(ftp_location);
+    }
+    Err(error::Error::NoWorkLocation)
+}
+fn restore(
+    work_folder: Option<&Path>,
+    ftp_location: FtpLocation,
+    snapshot: Option<String>,
+    keep: usize,
+) -> error::Result<Snapshot> {
+    if let Some(folder) = work_folder {
+        return restore_local(folder, snapshot.as_deref(), keep);
+    } else if !ftp_location.address.is_empty() {
+        return restore_over_sftp(ftp_location, snapshot, keep);
+    }
+    Err(error::Error::NoWorkLocation)
+}
+fn plan(
+    work_folder: Option<&Path>,
+    ftp_location: FtpLocation,
+    pack_source: PackSource,
+    options: RunOptions,
+) -> error::Result<diff::PackDiff> {
+    if let Some(folder) = work_folder {
+        return diff_local(folder, &pack_source, &options);
+    } else if !ftp_location.address.is_empty() {
+        return diff_over_sftp(ftp_location, pack_source, options);
     }
-    Err("No work location set!")
+    Err(error::Error::NoWorkLocation)
+}
+fn run(
+    work_folder: Option<&Path>,
+    ftp_location: FtpLocation,
+    pack_source: PackSource,
+    options: RunOptions,
+) -> error::Result<diff::PackDiff> {
+    if let Some(folder) = work_folder {
+        return run_local(folder, &pack_source, &options);
+    } else if !ftp_location.address.is_empty() {
+        return run_over_sftp(ftp_location, pack_source, options);
+    }
+    Err(error::Error::NoWorkLocation)
 }
 #[derive(Clone)]
 enum PackSource {
@@ -186,6 +887,24 @@ enum PackSource {
     Url(String),
     None,
 }
+impl PackSource {
+    /// A URL when it starts with `http://` or `https://`, a file path otherwise.
+    fn parse(source: &str) -> Self {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            PackSource::Url(source.to_string())
+        } else {
+            PackSource::FromFile(PathBuf::from(source))
+        }
+    }
+    /// The source as typed by the user, the inverse of [`PackSource::parse`].
+    fn setting(&self) -> Option<String> {
+        match self {
+            PackSource::FromFile(path) => Some(path.display().to_string()),
+            PackSource::Url(url) => Some(url.clone()),
+            PackSource::None => None,
+        }
+    }
+}
 impl Display for PackSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -195,3 +914,72 @@ impl Display for PackSource {
         }
     }
 }
+/// How to log in to an SFTP target.
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    Password,
+    /// A private key file, optionally encrypted with a passphrase.
+    KeyFile,
+    /// Keys offered by the running SSH agent.
+    Agent,
+}
+/// Which side of the game the pack is installed for, used to filter files by their `env` flags.
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Side {
+    Server,
+    Client,
+}
+/// What to do with files that are marked optional for the chosen side.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OptionalPolicy {
+    Install,
+    Skip,
+}
+/// What to do when a pack file would land on a file that is not managed by the updater, most
+/// likely one the admin put there by hand.
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+pub enum CollisionPolicy {
+    /// Keep the existing file and do not install the pack's.
+    Skip,
+    Overwrite,
+    /// Keep the existing file next to the pack's with a `.nmu-backup` suffix.
+    Backup,
+}
+/// Number of files downloaded at the same time unless configured otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+#[derive(Clone)]
+pub struct RunOptions {
+    side: Side,
+    optional: OptionalPolicy,
+    collisions: CollisionPolicy,
+    /// Maximum number of files downloaded at the same time.
+    concurrency: usize,
+    progress: progress::Progress,
+    cancel: progress::Cancel,
+    /// Download cache to read from and add to, if any.
+    cache: Option<cache::Cache>,
+    /// Number of backup snapshots kept in the work folder, 0 to not back up before updating.
+    keep_backups: usize,
+    /// Install or upgrade the server's mod loader to the version the pack depends on.
+    install_loader: bool,
+    /// Apply a pack made for a different Minecraft version than the server runs.
+    force_minecraft_version: bool,
+}
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            side: Side::Server,
+            optional: OptionalPolicy::Install,
+            collisions: CollisionPolicy::Skip,
+            concurrency: DEFAULT_CONCURRENCY,
+            progress: progress::Progress::default(),
+            cancel: progress::Cancel::default(),
+            cache: cache::Cache::at_default_dir(),
+            keep_backups: backup::DEFAULT_KEEP_BACKUPS,
+            install_loader: false,
+            force_minecraft_version: false,
+        }
+    }
+}
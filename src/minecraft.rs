@@ -0,0 +1,348 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use russh_sftp::client::SftpSession;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt},
+    runtime::Handle,
+};
+use zip::ZipArchive;
+
+use crate::{
+    config::UpdaterConfig,
+    error::{Error, IoContext, Result, SftpContext},
+    log,
+    mrpack::Mrpack,
+    RunOptions,
+};
+
+/// Vanilla server jar, also what the Fabric and Quilt launchers start.
+const SERVER_JAR: &str = "server.jar";
+/// Folders holding one subfolder per Minecraft version: where the vanilla server unpacks itself
+/// since 1.18 and where the Forge and NeoForge installers put it, as `<version>-<MCP timestamp>`.
+const VERSION_FOLDERS: [&str; 2] = ["versions", "libraries/net/minecraft/server"];
+/// Read size for remote jars, so the zip directory is not fetched a few bytes at a time.
+const REMOTE_READ_BUFFER: usize = 64 * 1024;
+
+/// Minecraft version the server in `folder` runs, if it can be told from its files.
+pub fn minecraft_version_local(folder: &Path) -> Result<Option<String>> {
+    for versions in VERSION_FOLDERS {
+        let dir = folder.join(versions);
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&dir).at(&dir)? {
+            let entry = entry.at(&dir)?;
+            if entry.file_type().at(entry.path())?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        if let Some(version) = only(names) {
+            return Ok(Some(version));
+        }
+    }
+    let jar = folder.join(SERVER_JAR);
+    if !jar.exists() {
+        return Ok(None);
+    }
+    Ok(version_in_jar(fs::File::open(&jar).at(&jar)?))
+}
+/// The same as [`minecraft_version_local`], for the work folder of an SFTP session.
+pub async fn minecraft_version_remote(sftp: &SftpSession) -> Result<Option<String>> {
+    for versions in VERSION_FOLDERS {
+        let Ok(entries) = sftp.read_dir(versions).await else {
+            continue;
+        };
+        let names = entries
+            .filter(|entry| entry.file_type().is_dir())
+            .map(|entry| entry.file_name())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        if let Some(version) = only(names) {
+            return Ok(Some(version));
+        }
+    }
+    if !sftp.try_exists(SERVER_JAR).await.unwrap_or(false) {
+        return Ok(None);
+    }
+    let jar = sftp.open(SERVER_JAR).await.at(SERVER_JAR)?;
+    Ok(version_in_remote_jar(jar).await)
+}
+/// [`version_in_jar`] for a jar read asynchronously. `zip` only reads the directory at the end
+/// and `version.json` itself.
+async fn version_in_remote_jar<F>(jar: F) -> Option<String>
+where
+    F: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let blocking = Blocking {
+        inner: jar,
+        runtime: Handle::current(),
+    };
+    tokio::task::spawn_blocking(move || {
+        version_in_jar(BufReader::with_capacity(REMOTE_READ_BUFFER, blocking))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+/// Blocking reads and seeks of an async file, for use on a blocking thread of the runtime.
+struct Blocking<F> {
+    inner: F,
+    runtime: Handle,
+}
+impl<F: AsyncRead + Unpin> Read for Blocking<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.inner.read(buf))
+    }
+}
+impl<F: AsyncSeek + Unpin> Seek for Blocking<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.runtime.block_on(self.inner.seek(pos))
+    }
+}
+/// The Minecraft version the version folders are named after, unless there are several and it is
+/// not clear which one runs.
+fn only(names: Vec<String>) -> Option<String> {
+    let versions: BTreeSet<_> = names.iter().map(|name| without_timestamp(name)).collect();
+    match versions.len() {
+        1 => versions.into_iter().next().map(str::to_string),
+        0 => None,
+        _ => {
+            log!("Found several Minecraft versions: {versions:?}");
+            None
+        }
+    }
+}
+/// `1.21.1` for a Forge folder name like `1.21.1-20240808.144430`, other names unchanged.
+fn without_timestamp(name: &str) -> &str {
+    let Some((version, stamp)) = name.rsplit_once('-') else {
+        return name;
+    };
+    let is_timestamp = stamp.split_once('.').is_some_and(|(date, time)| {
+        date.len() == 8
+            && time.len() == 6
+            && date.bytes().chain(time.bytes()).all(|b| b.is_ascii_digit())
+    });
+    if is_timestamp {
+        version
+    } else {
+        name
+    }
+}
+/// `id` from the `version.json` Mojang puts in every server jar.
+fn version_in_jar(jar: impl Read + Seek) -> Option<String> {
+    let mut zip = ZipArchive::new(jar).ok()?;
+    let mut json = String::new();
+    zip.by_name("version.json")
+        .ok()?
+        .read_to_string(&mut json)
+        .ok()?;
+    let version: serde_json::Value = serde_json::from_str(&json).ok()?;
+    Some(version.get("id")?.as_str()?.to_string())
+}
+/// The version the server runs as detected, or as recorded when the updater installed its loader.
+pub fn server_minecraft_version(
+    detected: Option<String>,
+    previous: Option<&UpdaterConfig>,
+) -> Option<String> {
+    detected.or_else(|| {
+        previous
+            .and_then(|previous| previous.installed_loader.as_ref())
+            .map(|loader| loader.minecraft_version.clone())
+    })
+}
+/// Refuses packs made for a different Minecraft version than the server runs, unless forced.
+pub fn check_minecraft_version(
+    pack: &Mrpack,
+    server: Option<&str>,
+    options: &RunOptions,
+) -> Result<()> {
+    let Some(wanted) = pack.dependencies.get("minecraft") else {
+        return Ok(());
+    };
+    match server {
+        Some(server) if server != wanted => {
+            if options.force_minecraft_version {
+                log!("Pack targets Minecraft {wanted} but the server runs {server}, forced to continue");
+                Ok(())
+            } else {
+                Err(Error::MinecraftMismatch {
+                    pack: wanted.clone(),
+                    server: server.to_string(),
+                })
+            }
+        }
+        Some(_) => Ok(()),
+        None => {
+            log!("Could not tell which Minecraft version the work folder runs, not checking it against the pack");
+            Ok(())
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+
+    use tokio::io::ReadBuf;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    fn pack_for(minecraft: &str) -> Mrpack {
+        serde_json::from_value(serde_json::json!({
+            "formatVersion": 1,
+            "game": "minecraft",
+            "versionId": "1.0",
+            "files": [],
+            "dependencies": { "minecraft": minecraft, "fabric-loader": "0.15.0" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_the_version_from_the_server_jar() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut zip = ZipWriter::new(fs::File::create(dir.path().join(SERVER_JAR)).unwrap());
+        zip.start_file("version.json", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(br#"{"id": "1.20.1", "name": "1.20.1"}"#)
+            .unwrap();
+        zip.finish().unwrap();
+        assert_eq!(
+            minecraft_version_local(dir.path()).unwrap().as_deref(),
+            Some("1.20.1")
+        );
+    }
+
+    #[test]
+    fn reads_the_version_from_the_unpacked_server() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(minecraft_version_local(dir.path()).unwrap(), None);
+        fs::create_dir_all(dir.path().join("libraries/net/minecraft/server/1.21.1")).unwrap();
+        assert_eq!(
+            minecraft_version_local(dir.path()).unwrap().as_deref(),
+            Some("1.21.1")
+        );
+    }
+
+    #[test]
+    fn reads_the_version_from_a_forge_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("libraries/net/minecraft/server");
+        fs::create_dir_all(server.join("1.21.1-20240808.144430")).unwrap();
+        assert_eq!(
+            minecraft_version_local(dir.path()).unwrap().as_deref(),
+            Some("1.21.1")
+        );
+        // NeoForge puts the plain version next to the one with the timestamp.
+        fs::create_dir_all(server.join("1.21.1")).unwrap();
+        assert_eq!(
+            minecraft_version_local(dir.path()).unwrap().as_deref(),
+            Some("1.21.1")
+        );
+        fs::create_dir_all(server.join("1.20.1-20230612.114412")).unwrap();
+        assert_eq!(minecraft_version_local(dir.path()).unwrap(), None);
+        assert_eq!(without_timestamp("1.21-pre1"), "1.21-pre1");
+    }
+
+    /// Counts the bytes read through it.
+    struct Counting {
+        inner: tokio::fs::File,
+        read: Arc<AtomicUsize>,
+    }
+    impl AsyncRead for Counting {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let before = buf.filled().len();
+            let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+            self.read
+                .fetch_add(buf.filled().len() - before, Ordering::Relaxed);
+            poll
+        }
+    }
+    impl AsyncSeek for Counting {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+    /// Reads the jar at `path` as if it were remote, returning the version and the bytes read.
+    fn remote_version(path: &Path) -> (Option<String>, usize) {
+        let read = Arc::new(AtomicUsize::new(0));
+        let version = crate::error::runtime().unwrap().block_on(async {
+            let inner = tokio::fs::File::open(path).await.unwrap();
+            version_in_remote_jar(Counting {
+                inner,
+                read: read.clone(),
+            })
+            .await
+        });
+        (version, read.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn reads_version_json_without_reading_the_whole_jar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SERVER_JAR);
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("net/minecraft/Big.class", stored).unwrap();
+        zip.write_all(&[7; 1_000_000]).unwrap();
+        zip.start_file("version.json", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(br#"{"id": "1.20.4", "name": "1.20.4"}"#)
+            .unwrap();
+        zip.start_file("META-INF/MANIFEST.MF", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"Main-Class: net.minecraft.bundler.Main")
+            .unwrap();
+        zip.finish().unwrap();
+        let (version, read) = remote_version(&path);
+        assert_eq!(version.as_deref(), Some("1.20.4"));
+        assert!(read < 300_000, "read {read} bytes");
+    }
+
+    #[test]
+    fn treats_a_damaged_remote_jar_as_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SERVER_JAR);
+        let mut damaged = vec![0; 1000];
+        damaged.extend_from_slice(b"PK\x05\x06");
+        damaged.extend_from_slice(&[0xff; 18]);
+        fs::write(&path, damaged).unwrap();
+        assert_eq!(remote_version(&path).0, None);
+    }
+
+    #[test]
+    fn refuses_other_minecraft_versions_unless_forced() {
+        let mut options = RunOptions::default();
+        let pack = pack_for("1.21.1");
+        assert!(check_minecraft_version(&pack, Some("1.21.1"), &options).is_ok());
+        assert!(check_minecraft_version(&pack, None, &options).is_ok());
+        assert!(matches!(
+            check_minecraft_version(&pack, Some("1.20.1"), &options),
+            Err(Error::MinecraftMismatch { .. })
+        ));
+        options.force_minecraft_version = true;
+        assert!(check_minecraft_version(&pack, Some("1.20.1"), &options).is_ok());
+    }
+}
@@ -1,141 +1,1268 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs,
-    io::{self, Read, Write},
-    path::{Path, PathBuf},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
+use futures::{stream, StreamExt, TryStreamExt};
 use russh_sftp::client::SftpSession;
 use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWriteExt, runtime::Runtime};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zip::ZipArchive;
 
-use crate::{config::UpdaterConfig, PackSource};
+use crate::{
+    config::{now, FileRecord, InstalledLoader, UpdaterConfig},
+    diff::PackDiff,
+    error::{runtime, Error, IoContext, Result, SftpContext},
+    loader::{loader_files, loader_to_install, prepare_loader},
+    log,
+    minecraft::{
+        check_minecraft_version, minecraft_version_local, minecraft_version_remote,
+        server_minecraft_version,
+    },
+    progress::{Progress, ProgressEvent},
+    transaction::{RemoteTransaction, Transaction},
+    CollisionPolicy, OptionalPolicy, PackSource, RunOptions, Side,
+};
+const INDEX_NAME: &str = "modrinth.index.json";
+/// `formatVersion` of the packs this updater understands.
+const FORMAT_VERSION: u32 = 1;
+/// How often a mirror is tried before moving on to the next one.
+const ATTEMPTS_PER_MIRROR: u32 = 3;
+/// Wait before the first retry, doubled after every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest time without receiving any data before a download counts as stalled.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Folders inside the mrpack whose contents are copied verbatim into the work folder.
+/// Later folders take precedence over earlier ones.
+fn override_folders(side: Side) -> [&'static str; 2] {
+    match side {
+        Side::Server => ["overrides", "server-overrides"],
+        Side::Client => ["overrides", "client-overrides"],
+    }
+}
+/// Stages the pack from `source` into the transaction. Returns the new `updater.json` and the
+/// diff that was applied, including the files left alone.
 pub fn update_from_mrpack_to_local(
     source: &PackSource,
-    work_folder: &Path,
-) -> Result<UpdaterConfig, &'static str> {
-    let rt = Runtime::new().unwrap();
-    let pack = rt.block_on(get_mrpack(source));
-    match pack {
-        Ok((pack, url_option)) => match transfer_pack_files_to_local(pack, work_folder) {
-            Ok(vec) => Ok(UpdaterConfig {
-                files: vec,
-                pack_endpoint: url_option,
-            }),
-            Err(str) => Err(str),
-        },
-        Err(str) => Err(str),
+    transaction: &mut Transaction,
+    options: &RunOptions,
+    previous: Option<&UpdaterConfig>,
+) -> Result<(UpdaterConfig, PackDiff)> {
+    let runtime = runtime()?;
+    let FetchedPack {
+        pack,
+        mut zip,
+        endpoint,
+        etag,
+        index_hash,
+    } = runtime.block_on(get_mrpack(source))?;
+    let server_minecraft =
+        server_minecraft_version(minecraft_version_local(transaction.folder())?, previous);
+    check_minecraft_version(&pack, server_minecraft.as_deref(), options)?;
+    let mut config = pack_config(&pack, endpoint, etag, index_hash, previous);
+    config.server_minecraft_version = server_minecraft;
+    let loader = match loader_to_install(&pack, previous, options) {
+        Some(loader) => {
+            options.cancel.check()?;
+            let prepared = runtime.block_on(prepare_loader(&loader))?;
+            Some((loader, prepared))
+        }
+        None => None,
+    };
+    let mut entries = wanted_entries(pack, options);
+    let mut overrides = read_overrides(&mut zip, options.side)?;
+    let mut hashes = expected_hashes(&entries, &overrides);
+    let mut loader_files = loader_files(loader.as_ref().map(|(_, prepared)| prepared), previous);
+    expect_loader_files(&mut hashes, &mut loader_files);
+    let mut diff = PackDiff::between(previous, &hashes);
+    for path in &diff.removed {
+        check_pack_path(path)?;
     }
+    check_symlinks_local(transaction.folder(), hashes.keys().chain(&diff.removed))?;
+    diff.find_collisions(|path| transaction.folder().join(path).exists());
+    diff.apply_collision_policy(options.collisions);
+    if let Some((loader, _)) = &loader {
+        check_loader_not_skipped(loader, &diff, &loader_files)?;
+    }
+    leave_alone(&diff, &mut entries, &mut overrides, &mut hashes);
+    loader_files.retain(|path, _| hashes.contains_key(path));
+    log!("{diff}");
+    for path in &diff.removed {
+        transaction.remove(path);
+    }
+    for path in collisions_to_back_up(&diff, options) {
+        transaction.back_up(path);
+    }
+    let served = runtime.block_on(transfer_pack_files_to_local(
+        &entries,
+        transaction,
+        &diff,
+        options,
+    ))?;
+    config.files = file_records(
+        &entries,
+        &overrides,
+        &loader_files,
+        &hashes,
+        &diff,
+        &served,
+        previous,
+    );
+    extract_overrides_to_local(overrides, transaction, &diff)?;
+    if let Some((loader, prepared)) = loader {
+        let written = loader_files.keys().filter(|path| diff.needs_write(path));
+        prepared.stage_local(transaction, written)?;
+        config.server_minecraft_version = Some(loader.minecraft_version.clone());
+        config.installed_loader = Some(loader);
+    }
+    Ok((config, diff))
 }
 pub async fn update_from_mrpack_to_remote(
     source: &PackSource,
     sftp: &mut SftpSession,
-) -> Result<UpdaterConfig, &'static str> {
-    match get_mrpack(source).await {
-        Ok((pack, url_option)) => match transfer_pack_files_to_remote(pack, sftp).await {
-            Ok(vec) => Ok(UpdaterConfig {
-                files: vec,
-                pack_endpoint: url_option,
+    transaction: &mut RemoteTransaction,
+    options: &RunOptions,
+    previous: Option<&UpdaterConfig>,
+) -> Result<(UpdaterConfig, PackDiff)> {
+    let FetchedPack {
+        pack,
+        mut zip,
+        endpoint,
+        etag,
+        index_hash,
+    } = get_mrpack(source).await?;
+    let server_minecraft =
+        server_minecraft_version(minecraft_version_remote(sftp).await?, previous);
+    check_minecraft_version(&pack, server_minecraft.as_deref(), options)?;
+    let mut config = pack_config(&pack, endpoint, etag, index_hash, previous);
+    config.server_minecraft_version = server_minecraft;
+    let loader = match loader_to_install(&pack, previous, options) {
+        Some(loader) => {
+            options.cancel.check()?;
+            let prepared = prepare_loader(&loader).await?;
+            Some((loader, prepared))
+        }
+        None => None,
+    };
+    let mut entries = wanted_entries(pack, options);
+    let mut overrides = read_overrides(&mut zip, options.side)?;
+    let mut hashes = expected_hashes(&entries, &overrides);
+    let mut loader_files = loader_files(loader.as_ref().map(|(_, prepared)| prepared), previous);
+    expect_loader_files(&mut hashes, &mut loader_files);
+    let mut diff = PackDiff::between(previous, &hashes);
+    for path in &diff.removed {
+        check_pack_path(path)?;
+    }
+    check_symlinks_remote(sftp, hashes.keys().chain(&diff.removed)).await?;
+    find_collisions_remote(sftp, &mut diff).await?;
+    diff.apply_collision_policy(options.collisions);
+    if let Some((loader, _)) = &loader {
+        check_loader_not_skipped(loader, &diff, &loader_files)?;
+    }
+    leave_alone(&diff, &mut entries, &mut overrides, &mut hashes);
+    loader_files.retain(|path, _| hashes.contains_key(path));
+    log!("{diff}");
+    for path in &diff.removed {
+        transaction.remove(path);
+    }
+    for path in collisions_to_back_up(&diff, options) {
+        transaction.back_up(path);
+    }
+    let served = transfer_pack_files_to_remote(&entries, sftp, transaction, &diff, options).await?;
+    config.files = file_records(
+        &entries,
+        &overrides,
+        &loader_files,
+        &hashes,
+        &diff,
+        &served,
+        previous,
+    );
+    extract_overrides_to_remote(overrides, sftp, transaction, &diff).await?;
+    if let Some((loader, prepared)) = loader {
+        let written = loader_files.keys().filter(|path| diff.needs_write(path));
+        prepared.stage_remote(sftp, transaction, written).await?;
+        config.server_minecraft_version = Some(loader.minecraft_version.clone());
+        config.installed_loader = Some(loader);
+    }
+    Ok((config, diff))
+}
+/// The `updater.json` for `pack`, still without any files.
+fn pack_config(
+    pack: &Mrpack,
+    endpoint: Option<String>,
+    etag: Option<String>,
+    index_hash: String,
+    previous: Option<&UpdaterConfig>,
+) -> UpdaterConfig {
+    let loader = pack.loader();
+    UpdaterConfig {
+        pack_endpoint: endpoint,
+        pack_name: pack.name.clone(),
+        pack_version: pack.version_id.clone(),
+        pack_summary: pack.summary.clone(),
+        minecraft_version: pack.dependencies.get("minecraft").cloned(),
+        loader: loader.map(|(name, _)| name.to_string()),
+        loader_version: loader.map(|(_, version)| version.to_string()),
+        pack_etag: etag,
+        index_hash: Some(index_hash),
+        installed_loader: previous.and_then(|previous| previous.installed_loader.clone()),
+        protected: previous
+            .map(|previous| previous.protected.clone())
+            .unwrap_or_default(),
+        ..UpdaterConfig::with_files(BTreeMap::new())
+    }
+}
+/// What the pack at `source` is, without installing it.
+pub async fn read_pack_info(source: &PackSource) -> Result<UpdaterConfig> {
+    let FetchedPack {
+        pack,
+        endpoint,
+        etag,
+        index_hash,
+        ..
+    } = get_mrpack(source).await?;
+    Ok(pack_config(&pack, endpoint, etag, index_hash, None))
+}
+/// Adds the loader files to the files the update expects. Paths the pack has stay the pack's.
+fn expect_loader_files(
+    hashes: &mut BTreeMap<PathBuf, String>,
+    loader_files: &mut BTreeMap<PathBuf, FileRecord>,
+) {
+    loader_files.retain(|path, _| !hashes.contains_key(path));
+    for (path, file) in loader_files.iter() {
+        if let Some(sha512) = &file.sha512 {
+            hashes.insert(path.clone(), sha512.clone());
+        }
+    }
+}
+/// Refuses to install a loader when the collision policy keeps unmanaged files in place of some of
+/// its files, as it would be recorded as installed while the old files are still there. Protected
+/// paths are kept on purpose and do not count.
+fn check_loader_not_skipped(
+    loader: &InstalledLoader,
+    diff: &PackDiff,
+    loader_files: &BTreeMap<PathBuf, FileRecord>,
+) -> Result<()> {
+    let in_the_way: Vec<PathBuf> = diff
+        .skipped
+        .iter()
+        .filter(|path| loader_files.contains_key(*path) && diff.collisions.contains(path))
+        .cloned()
+        .collect();
+    if in_the_way.is_empty() {
+        return Ok(());
+    }
+    Err(Error::LoaderFilesInTheWay {
+        loader: loader.to_string(),
+        paths: in_the_way,
+    })
+}
+/// Records of the files the update installs. Files that are not written again keep their
+/// install time. `served` is the URL each downloaded file came from, files copied from the
+/// download cache or not written again keep the source recorded for the same contents.
+fn file_records(
+    entries: &[PackEntry],
+    overrides: &[(PathBuf, Vec<u8>)],
+    loader_files: &BTreeMap<PathBuf, FileRecord>,
+    hashes: &BTreeMap<PathBuf, String>,
+    diff: &PackDiff,
+    served: &BTreeMap<PathBuf, String>,
+    previous: Option<&UpdaterConfig>,
+) -> BTreeMap<PathBuf, FileRecord> {
+    let now = now();
+    let installed_at = |path: &Path| {
+        previous
+            .filter(|_| !diff.needs_write(path))
+            .and_then(|previous| previous.files.get(path))
+            .and_then(|file| file.installed_at.clone())
+            .or_else(|| Some(now.clone()))
+    };
+    let mut records = BTreeMap::new();
+    for entry in entries {
+        let record = FileRecord {
+            sha512: hashes.get(&entry.path).cloned(),
+            size: entry.file_size,
+            source: served.get(&entry.path).cloned().or_else(|| {
+                previous
+                    .and_then(|previous| previous.files.get(&entry.path))
+                    .filter(|file| file.sha512 == hashes.get(&entry.path).cloned())
+                    .and_then(|file| file.source.clone())
             }),
-            Err(str) => Err(str),
-        },
-        Err(str) => Err(str),
+            installed_at: installed_at(&entry.path),
+            loader: false,
+        };
+        records.insert(entry.path.clone(), record);
+    }
+    for (path, contents) in overrides {
+        let record = FileRecord {
+            sha512: hashes.get(path).cloned(),
+            size: Some(contents.len() as u64),
+            source: None,
+            installed_at: installed_at(path),
+            loader: false,
+        };
+        records.insert(path.clone(), record);
     }
+    for (path, file) in loader_files {
+        let record = FileRecord {
+            installed_at: installed_at(path),
+            ..file.clone()
+        };
+        records.insert(path.clone(), record);
+    }
+    records
 }
-pub async fn get_mrpack(source: &PackSource) -> Result<(Mrpack, Option<String>), &'static str> {
+/// Fills in [`PackDiff::collisions`] for a work folder reached over SFTP.
+pub async fn find_collisions_remote(sftp: &SftpSession, diff: &mut PackDiff) -> Result<()> {
+    let mut collisions = Vec::new();
+    for path in &diff.added {
+        if sftp
+            .try_exists(path.to_string_lossy())
+            .await
+            .at(path.to_string_lossy())?
+        {
+            collisions.push(path.clone());
+        }
+    }
+    diff.collisions = collisions;
+    Ok(())
+}
+/// Drops the files the diff skips from the update, so they are neither written nor recorded as
+/// managed in `updater.json`.
+fn leave_alone(
+    diff: &PackDiff,
+    entries: &mut Vec<PackEntry>,
+    overrides: &mut Vec<(PathBuf, Vec<u8>)>,
+    hashes: &mut BTreeMap<PathBuf, String>,
+) {
+    for path in &diff.skipped {
+        log!("Leaving {} alone", path.display());
+        hashes.remove(path);
+    }
+    entries.retain(|entry| !diff.skipped.contains(&entry.path));
+    overrides.retain(|(path, _)| !diff.skipped.contains(path));
+}
+/// Unmanaged files in the way that the policy keeps a copy of.
+fn collisions_to_back_up<'a>(diff: &'a PackDiff, options: &RunOptions) -> &'a [PathBuf] {
+    match options.collisions {
+        CollisionPolicy::Backup => &diff.collisions,
+        CollisionPolicy::Skip | CollisionPolicy::Overwrite => &[],
+    }
+}
+/// Computes what an update would change without downloading or writing any pack files.
+pub async fn diff_pack(
+    source: &PackSource,
+    options: &RunOptions,
+    previous: Option<&UpdaterConfig>,
+    server_minecraft: Option<&str>,
+) -> Result<PackDiff> {
+    let FetchedPack { pack, mut zip, .. } = get_mrpack(source).await?;
+    check_minecraft_version(&pack, server_minecraft, options)?;
+    if let Some(loader) = loader_to_install(&pack, previous, options) {
+        log!("Would install {loader}");
+    }
+    let entries = wanted_entries(pack, options);
+    let overrides = read_overrides(&mut zip, options.side)?;
+    // Which files a new loader brings is only known after running its installer, so the plan
+    // keeps the installed loader's files.
+    let mut hashes = expected_hashes(&entries, &overrides);
+    expect_loader_files(&mut hashes, &mut loader_files(None, previous));
+    Ok(PackDiff::between(previous, &hashes))
+}
+/// A pack read from its source, with what is needed to recognise it again later.
+pub struct FetchedPack {
+    pub(crate) pack: Mrpack,
+    pub(crate) zip: ZipArchive<fs::File>,
+    /// URL the pack was downloaded from, if any.
+    pub(crate) endpoint: Option<String>,
+    pub(crate) etag: Option<String>,
+    /// sha512 of `modrinth.index.json`.
+    pub(crate) index_hash: String,
+}
+pub async fn get_mrpack(source: &PackSource) -> Result<FetchedPack> {
     match source {
         PackSource::FromFile(path) => {
-            if let Ok(file) = fs::File::open(path) {
-                if let Ok(mut zip) = zip::ZipArchive::new(file) {
-                    if let Ok(mut pack_file) = zip.by_name("modrinth.index.json") {
-                        let mut contents = String::new();
-                        pack_file
-                            .read_to_string(&mut contents)
-                            .expect("Could not read file content ?");
-                        return match serde_json::from_str::<Mrpack>(contents.as_str()) {
-                            Ok(pack) => Result::Ok((pack, None)),
-                            Err(_) => Result::Err("Could not deserialize pack file"),
-                        };
-                    }
-                }
-            }
-            Result::Err("Could not open .mrpack file")
+            let file = fs::File::open(path).at(path)?;
+            let mut zip = zip::ZipArchive::new(file)?;
+            let (pack, index_hash) = read_index(&mut zip)?;
+            Ok(FetchedPack {
+                pack,
+                zip,
+                endpoint: None,
+                etag: None,
+                index_hash,
+            })
         }
         PackSource::Url(url) => {
-            if let Ok(response) = reqwest::get(url).await {
-                let mut tmpfile = tempfile::tempfile().expect("Could not create tempfile");
-                tmpfile
-                    .write_all(&response.bytes().await.unwrap())
-                    .expect("Could not copy to tempfile");
-                if let Ok(mut zip) = zip::ZipArchive::new(tmpfile) {
-                    if let Ok(mut pack_file) = zip.by_name("modrinth.index.json") {
-                        let mut contents = String::new();
-                        pack_file
-                            .read_to_string(&mut contents)
-                            .expect("Could not read file content ?");
-                        return match serde_json::from_str::<Mrpack>(contents.as_str()) {
-                            Ok(pack) => Result::Ok((pack, Some(url.clone()))),
-                            Err(_) => Result::Err("Could not deserialize pack file"),
-                        };
-                    }
-                }
-                return Err("Could not unzip downloaded mrpack");
+            let http = |source| Error::Http {
+                url: url.to_string(),
+                source,
+            };
+            let response = reqwest::get(url)
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(http)?;
+            let etag = etag(&response);
+            let bytes = response.bytes().await.map_err(http)?;
+            let mut tmpfile = tempfile::tempfile().at(std::env::temp_dir())?;
+            tmpfile.write_all(&bytes).at(std::env::temp_dir())?;
+            let mut zip = zip::ZipArchive::new(tmpfile)?;
+            let (pack, index_hash) = read_index(&mut zip)?;
+            Ok(FetchedPack {
+                pack,
+                zip,
+                endpoint: Some(url.clone()),
+                etag,
+                index_hash,
+            })
+        }
+        PackSource::None => Err(Error::NoPackSource),
+    }
+}
+pub fn etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+/// Returns the parsed index together with its sha512.
+fn read_index(zip: &mut ZipArchive<fs::File>) -> Result<(Mrpack, String)> {
+    let mut pack_file = zip.by_name(INDEX_NAME)?;
+    let mut contents = String::new();
+    pack_file.read_to_string(&mut contents).at(INDEX_NAME)?;
+    let pack = serde_json::from_str::<Mrpack>(contents.as_str()).map_err(|source| Error::Json {
+        path: PathBuf::from(INDEX_NAME),
+        source,
+    })?;
+    if pack.format_version != FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat(pack.format_version));
+    }
+    if pack.game != "minecraft" {
+        return Err(Error::NotMinecraft(pack.game));
+    }
+    for entry in &pack.files {
+        check_pack_path(&entry.path)?;
+    }
+    Ok((pack, hex::encode(Sha512::digest(contents.as_bytes()))))
+}
+/// Rejects paths from a pack that could point outside the work folder: absolute ones and ones
+/// with `..` in them.
+pub(crate) fn check_pack_path(path: &Path) -> Result<()> {
+    let mut components = path.components().peekable();
+    let safe = components.peek().is_some()
+        && components
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if safe {
+        Ok(())
+    } else {
+        Err(Error::UnsafePath {
+            path: path.to_path_buf(),
+        })
+    }
+}
+/// Rejects paths below a folder that already exists in the work folder as a symlink leading out
+/// of it, since files written there would end up outside.
+fn check_symlinks_local<'a>(folder: &Path, paths: impl Iterator<Item = &'a PathBuf>) -> Result<()> {
+    let root = folder.canonicalize().at(folder)?;
+    let mut checked = BTreeSet::new();
+    for path in paths {
+        let mut dir = folder.to_path_buf();
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            if !checked.insert(dir.clone()) {
+                continue;
+            }
+            let Ok(real) = dir.canonicalize() else {
+                break;
+            };
+            if !real.starts_with(&root) {
+                return Err(Error::UnsafePath { path: path.clone() });
             }
-            Err("Could not GET mrpack file")
         }
-        PackSource::None => Err("No pack source selected"),
     }
+    Ok(())
 }
-fn transfer_pack_files_to_local(pack: Mrpack, folder: &Path) -> Result<Vec<PathBuf>, &'static str> {
-    let mut paths = Vec::new();
-    for PackEntry { path, downloads } in pack.files {
-        if !downloads.is_empty() {
-            if let Ok(mut response) = reqwest::blocking::get(&downloads[0]) {
-                if let Ok(mut file) = fs::File::create(folder.join(&path)) {
-                    io::copy(&mut response, &mut file).expect("Could not write into created file");
-                    paths.push(path);
-                } else {
-                    return Err("Could not create file in mod directory");
-                }
-            } else {
-                return Err("Could not GET file from download link in pack definition");
+/// The same as [`check_symlinks_local`], for a work folder reached over SFTP.
+async fn check_symlinks_remote<'a>(
+    sftp: &SftpSession,
+    paths: impl Iterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    let root = PathBuf::from(sftp.canonicalize(".").await.at(".")?);
+    let mut checked = BTreeSet::new();
+    for path in paths {
+        let mut dir = PathBuf::new();
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            if !checked.insert(dir.clone()) {
+                continue;
+            }
+            let dir = dir.to_string_lossy();
+            if !sftp.try_exists(dir.clone()).await.unwrap_or(false) {
+                break;
+            }
+            let real = sftp.canonicalize(dir.clone()).await.at(dir)?;
+            if !Path::new(&real).starts_with(&root) {
+                return Err(Error::UnsafePath { path: path.clone() });
             }
-        } else {
-            return Err("File has no download links");
         }
     }
-    Ok(paths)
+    Ok(())
+}
+/// Downloads the pack files into the transaction. Returns the URL each downloaded file came from.
+async fn transfer_pack_files_to_local(
+    entries: &[PackEntry],
+    transaction: &mut Transaction,
+    diff: &PackDiff,
+    options: &RunOptions,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let entries: Vec<&PackEntry> = entries
+        .iter()
+        .filter(|entry| {
+            diff.needs_write(&entry.path) || !transaction.folder().join(&entry.path).exists()
+        })
+        .collect();
+    report_started(&entries, &options.progress);
+    let client = http_client()?;
+    let client = &client;
+    let staging: &Transaction = transaction;
+    let staged: Vec<(PathBuf, Option<String>)> = stream::iter(entries)
+        .map(|entry| async move {
+            let target = staging.staging_path(&entry.path)?;
+            let open = async || tokio::fs::File::create(&target).await.at(&target);
+            let url = fetch_entry(client, entry, open, &target, options).await?;
+            Ok::<_, Error>((entry.path.clone(), url))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect()
+        .await?;
+    for (path, _) in &staged {
+        transaction.mark_staged(path);
+    }
+    prune_cache(options);
+    Ok(served_by(staged))
 }
 async fn transfer_pack_files_to_remote(
-    pack: Mrpack,
+    entries: &[PackEntry],
     sftp: &mut SftpSession,
-) -> Result<Vec<PathBuf>, &'static str> {
-    let mut paths = Vec::new();
-    for PackEntry { path, downloads } in pack.files {
-        if !downloads.is_empty() {
-            if let Ok(response) = reqwest::get(&downloads[0]).await {
-                if let Ok(mut file) = sftp.create(path.to_string_lossy()).await {
-                    file.write_all(&response.bytes().await.unwrap())
-                        .await
-                        .expect("Could not write into created file!");
-                    paths.push(path);
-                } else {
-                    return Err("Could not create file in mod directory");
+    transaction: &mut RemoteTransaction,
+    diff: &PackDiff,
+    options: &RunOptions,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let mut wanted = Vec::new();
+    for entry in entries {
+        if diff.needs_write(&entry.path)
+            || !sftp
+                .try_exists(entry.path.to_string_lossy())
+                .await
+                .unwrap_or(false)
+        {
+            wanted.push(entry);
+        }
+    }
+    report_started(&wanted, &options.progress);
+    let client = http_client()?;
+    let client = &client;
+    let sftp: &SftpSession = sftp;
+    let staging: &RemoteTransaction = transaction;
+    let staged: Vec<(PathBuf, Option<String>)> = stream::iter(wanted)
+        .map(|entry| async move {
+            let target = staging.staging_path(sftp, &entry.path).await;
+            let open = async || sftp.create(target.clone()).await.at(&target);
+            let url = fetch_entry(client, entry, open, Path::new(&target), options).await?;
+            Ok::<_, Error>((entry.path.clone(), url))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect()
+        .await?;
+    for (path, _) in &staged {
+        transaction.mark_staged(path);
+    }
+    prune_cache(options);
+    Ok(served_by(staged))
+}
+/// The URLs that served the downloaded files, leaving out files copied from the cache.
+fn served_by(staged: Vec<(PathBuf, Option<String>)>) -> BTreeMap<PathBuf, String> {
+    staged
+        .into_iter()
+        .filter_map(|(path, url)| Some((path, url?)))
+        .collect()
+}
+/// Keeps the download cache within its size limit. A failure here does not fail the update.
+fn prune_cache(options: &RunOptions) {
+    if let Some(cache) = &options.cache {
+        match cache.prune() {
+            Ok(result) if result.removed > 0 => log!("{result}"),
+            Ok(_) => {}
+            Err(err) => log!("Could not prune download cache: {err}"),
+        }
+    }
+}
+fn report_started(entries: &[&PackEntry], progress: &Progress) {
+    progress.report(ProgressEvent::Started {
+        files: entries.len(),
+        total_bytes: entries.iter().filter_map(|entry| entry.file_size).sum(),
+    });
+}
+/// Writes a pack file into the writer made by `open`, from the download cache if it has the file
+/// and from the pack's mirrors otherwise. Downloads are added to the cache. Returns the URL the
+/// file was downloaded from, `None` if it came from the cache.
+async fn fetch_entry<W: AsyncWrite + Unpin>(
+    client: &reqwest::Client,
+    entry: &PackEntry,
+    open: impl AsyncFn() -> Result<W>,
+    target: &Path,
+    options: &RunOptions,
+) -> Result<Option<String>> {
+    let Some(cache) = &options.cache else {
+        let url = download_entry(client, entry, open, target, options).await?;
+        return finished(entry, options).map(|_| Some(url));
+    };
+    let sha512 = &entry.hashes.sha512;
+    if let Some(cached) = cache.get(sha512) {
+        match copy_verified(&cached, entry, open().await?, target).await {
+            Ok(()) => {
+                log!("{} served from cache", entry.path.display());
+                let size = entry.file_size.unwrap_or_default();
+                options.progress.report(ProgressEvent::File {
+                    path: entry.path.clone(),
+                    downloaded: size,
+                    size,
+                });
+                return finished(entry, options).map(|_| None);
+            }
+            Err(Error::HashMismatch { .. }) => {
+                log!(
+                    "Cached copy of {} is damaged, downloading it again",
+                    entry.path.display()
+                );
+                cache.evict(sha512);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let temp = cache.temp_file()?;
+    let temp_path = temp.to_path_buf();
+    let open_temp = async || tokio::fs::File::create(&temp_path).await.at(&temp_path);
+    let mut url = download_entry(client, entry, open_temp, &temp_path, options).await?;
+    match cache.insert(temp, sha512)? {
+        Some(cached) => copy_verified(&cached, entry, open().await?, target).await?,
+        None => url = download_entry(client, entry, open, target, options).await?,
+    }
+    finished(entry, options).map(|_| Some(url))
+}
+fn finished(entry: &PackEntry, options: &RunOptions) -> Result<()> {
+    options.progress.report(ProgressEvent::Finished {
+        path: entry.path.clone(),
+    });
+    Ok(())
+}
+/// Copies a cached file into `writer`, checking it still matches the pack's hashes.
+async fn copy_verified<W: AsyncWrite + Unpin>(
+    cached: &Path,
+    entry: &PackEntry,
+    mut writer: W,
+    target: &Path,
+) -> Result<()> {
+    let mut reader = tokio::fs::File::open(cached).await.at(cached)?;
+    let mut sha1 = Sha1::new();
+    let mut sha512 = Sha512::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).await.at(cached)?;
+        if read == 0 {
+            break;
+        }
+        sha1.update(&buffer[..read]);
+        sha512.update(&buffer[..read]);
+        writer.write_all(&buffer[..read]).await.at(target)?;
+    }
+    writer.flush().await.at(target)?;
+    verify_hashes(&entry.path, &entry.hashes, sha1, sha512)
+}
+pub(crate) fn http_client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()
+        .map_err(Error::HttpClient)
+}
+/// Downloads a pack file, trying each of its mirrors in order. Transient failures are retried on
+/// the same mirror with exponential backoff before moving on to the next one.
+/// `open` (re)creates the file being written, `target` is only used in error messages. Returns
+/// the URL that served the file.
+async fn download_entry<W: AsyncWrite + Unpin>(
+    client: &reqwest::Client,
+    entry: &PackEntry,
+    open: impl AsyncFn() -> Result<W>,
+    target: &Path,
+    options: &RunOptions,
+) -> Result<String> {
+    let mut last_error = Error::NoDownloads {
+        path: entry.path.clone(),
+    };
+    for url in &entry.downloads {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=ATTEMPTS_PER_MIRROR {
+            options.cancel.check()?;
+            let mut writer = open().await?;
+            match download_from(client, url, entry, &mut writer, target, options).await {
+                Ok(()) => {
+                    log!("{} served by {url}", entry.path.display());
+                    return Ok(url.clone());
                 }
-            } else {
-                return Err("Could not GET file from download link in pack definition");
+                Err(err @ (Error::Http { .. } | Error::HashMismatch { .. })) => {
+                    if attempt < ATTEMPTS_PER_MIRROR && is_transient(&err) {
+                        log!(
+                            "Downloading {} from {url} failed, retrying in {:?}: {err}",
+                            entry.path.display(),
+                            backoff
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                        continue;
+                    }
+                    log!("Could not get {} from {url}: {err}", entry.path.display());
+                    last_error = err;
+                    break;
+                }
+                Err(err) => return Err(err),
             }
+        }
+    }
+    Err(last_error)
+}
+/// Timeouts, dropped connections and server side errors are worth retrying, anything else is not.
+fn is_transient(err: &Error) -> bool {
+    let Error::Http { source, .. } = err else {
+        return false;
+    };
+    source.is_timeout()
+        || source.is_connect()
+        || source.is_body()
+        || source.status().is_some_and(|status| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+/// Streams a pack file from one URL into `writer` without holding it in memory, checking its
+/// hashes on the way.
+async fn download_from<W: AsyncWrite + Unpin>(
+    client: &reqwest::Client,
+    url: &str,
+    entry: &PackEntry,
+    writer: &mut W,
+    target: &Path,
+    options: &RunOptions,
+) -> Result<()> {
+    let http = |source| Error::Http {
+        url: url.to_string(),
+        source,
+    };
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(http)?;
+    let size = entry
+        .file_size
+        .or(response.content_length())
+        .unwrap_or_default();
+    let mut sha1 = Sha1::new();
+    let mut sha512 = Sha512::new();
+    let mut downloaded = 0;
+    while let Some(chunk) = response.chunk().await.map_err(http)? {
+        options.cancel.check()?;
+        sha1.update(&chunk);
+        sha512.update(&chunk);
+        writer.write_all(&chunk).await.at(target)?;
+        downloaded += chunk.len() as u64;
+        options.progress.report(ProgressEvent::File {
+            path: entry.path.clone(),
+            downloaded,
+            size,
+        });
+    }
+    writer.flush().await.at(target)?;
+    verify_hashes(&entry.path, &entry.hashes, sha1, sha512)
+}
+/// Reads every file under the override folders, keyed by its path relative to the work folder.
+fn read_overrides(zip: &mut ZipArchive<fs::File>, side: Side) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut overrides = Vec::new();
+    for folder in override_folders(side) {
+        for index in 0..zip.len() {
+            let mut entry = zip.by_index(index)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let Some(name) = entry.enclosed_name() else {
+                return Err(Error::UnsafePath {
+                    path: PathBuf::from(entry.name()),
+                });
+            };
+            if let Ok(path) = name.strip_prefix(folder) {
+                let path = path.to_path_buf();
+                check_pack_path(&path)?;
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).at(&name)?;
+                overrides.push((path, contents));
+            }
+        }
+    }
+    Ok(overrides)
+}
+fn extract_overrides_to_local(
+    overrides: Vec<(PathBuf, Vec<u8>)>,
+    transaction: &mut Transaction,
+    diff: &PackDiff,
+) -> Result<()> {
+    for (path, contents) in overrides {
+        if !diff.needs_write(&path) && transaction.folder().join(&path).exists() {
+            continue;
+        }
+        transaction.stage(&path, &contents)?;
+    }
+    Ok(())
+}
+async fn extract_overrides_to_remote(
+    overrides: Vec<(PathBuf, Vec<u8>)>,
+    sftp: &mut SftpSession,
+    transaction: &mut RemoteTransaction,
+    diff: &PackDiff,
+) -> Result<()> {
+    for (path, contents) in overrides {
+        if !diff.needs_write(&path)
+            && sftp
+                .try_exists(path.to_string_lossy())
+                .await
+                .unwrap_or(false)
+        {
+            continue;
+        }
+        transaction.stage(sftp, &path, &contents).await?;
+    }
+    Ok(())
+}
+fn wanted_entries(pack: Mrpack, options: &RunOptions) -> Vec<PackEntry> {
+    let mut entries = Vec::new();
+    for entry in pack.files {
+        if is_wanted(entry.env.as_ref(), options) {
+            entries.push(entry);
         } else {
-            return Err("File has no download links");
+            log!("Skipping {:?}, not wanted on this side", entry.path);
         }
     }
-    Ok(paths)
+    entries
+}
+/// Maps every path the pack will install to its sha512. Overrides win over downloaded files.
+fn expected_hashes(
+    entries: &[PackEntry],
+    overrides: &[(PathBuf, Vec<u8>)],
+) -> BTreeMap<PathBuf, String> {
+    let mut hashes = BTreeMap::new();
+    for entry in entries {
+        hashes.insert(entry.path.clone(), entry.hashes.sha512.to_lowercase());
+    }
+    for (path, contents) in overrides {
+        hashes.insert(path.clone(), hex::encode(Sha512::digest(contents)));
+    }
+    hashes
+}
+/// Files without an `env` block are installed on both sides.
+fn is_wanted(env: Option<&PackEnv>, options: &RunOptions) -> bool {
+    let Some(env) = env else {
+        return true;
+    };
+    let support = match options.side {
+        Side::Server => env.server,
+        Side::Client => env.client,
+    };
+    match support {
+        SideSupport::Required => true,
+        SideSupport::Optional => options.optional == OptionalPolicy::Install,
+        SideSupport::Unsupported => false,
+    }
+}
+fn verify_hashes(path: &Path, hashes: &PackHashes, sha1: Sha1, sha512: Sha512) -> Result<()> {
+    let sha1 = hex::encode(sha1.finalize());
+    let sha512 = hex::encode(sha512.finalize());
+    if !sha1.eq_ignore_ascii_case(&hashes.sha1) || !sha512.eq_ignore_ascii_case(&hashes.sha512) {
+        return Err(Error::HashMismatch {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Mrpack {
+    #[serde(rename = "formatVersion")]
+    format_version: u32,
+    game: String,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(rename = "versionId")]
+    pub(crate) version_id: Option<String>,
+    #[serde(default)]
+    pub(crate) summary: Option<String>,
     files: Vec<PackEntry>,
+    /// Versions of `minecraft` and of the mod loader the pack needs, such as `fabric-loader`.
+    #[serde(default)]
+    pub(crate) dependencies: BTreeMap<String, String>,
+}
+impl Mrpack {
+    /// Name and version of the mod loader the pack depends on.
+    pub fn loader(&self) -> Option<(&str, &str)> {
+        self.dependencies
+            .iter()
+            .find(|(name, _)| *name != "minecraft")
+            .map(|(name, version)| (name.as_str(), version.as_str()))
+    }
 }
 #[derive(Serialize, Deserialize, Debug)]
 struct PackEntry {
     path: PathBuf,
+    hashes: PackHashes,
+    env: Option<PackEnv>,
     downloads: Vec<String>,
+    #[serde(rename = "fileSize")]
+    file_size: Option<u64>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct PackHashes {
+    sha1: String,
+    sha512: String,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct PackEnv {
+    client: SideSupport,
+    server: SideSupport,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SideSupport {
+    Required,
+    Optional,
+    Unsupported,
+}
+
+#[cfg(test)]
+mod tests {
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+    use crate::{
+        config::{config_json, CONFIG_NAME},
+        local::run_local,
+    };
+
+    /// Writes a pack whose index lists `files` and that contains `extra` files as they are named
+    /// in the archive.
+    fn write_pack(dir: &Path, files: &[&str], extra: &[&str]) -> PathBuf {
+        let files = files
+            .iter()
+            .map(|path| {
+                serde_json::json!({
+                    "path": path,
+                    "hashes": { "sha1": "0".repeat(40), "sha512": "0".repeat(128) },
+                    "downloads": ["http://127.0.0.1:1/file.jar"],
+                })
+            })
+            .collect();
+        write_index(dir, files, extra)
+    }
+    fn write_index(dir: &Path, files: Vec<serde_json::Value>, extra: &[&str]) -> PathBuf {
+        let index = serde_json::json!({
+            "formatVersion": 1,
+            "game": "minecraft",
+            "versionId": "hostile",
+            "files": files,
+        });
+        let path = dir.join("hostile.mrpack");
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        zip.start_file(INDEX_NAME, SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(index.to_string().as_bytes()).unwrap();
+        for name in extra {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(b"pwned").unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+    /// Writes `files` into `server` with their own path as contents, and an `updater.json` that
+    /// manages them, marking those with `true` as loader files.
+    fn installed_before(server: &Path, files: &[(&str, bool)], protected: &[&str]) {
+        let mut records = BTreeMap::new();
+        for (path, loader) in files {
+            let installed = server.join(path);
+            fs::create_dir_all(installed.parent().unwrap()).unwrap();
+            fs::write(&installed, path).unwrap();
+            let record = FileRecord {
+                sha512: Some(hex::encode(Sha512::digest(path))),
+                loader: *loader,
+                ..FileRecord::default()
+            };
+            records.insert(PathBuf::from(path), record);
+        }
+        let config = UpdaterConfig {
+            protected: protected.iter().map(PathBuf::from).collect(),
+            ..UpdaterConfig::with_files(records)
+        };
+        fs::write(server.join(CONFIG_NAME), config_json(&config).unwrap()).unwrap();
+    }
+    /// Installs the pack into a fresh `server` folder next to it.
+    fn install(pack: PathBuf) -> Result<PackDiff> {
+        let folder = pack.parent().unwrap().join("server");
+        fs::create_dir_all(&folder).unwrap();
+        let options = RunOptions {
+            cache: None,
+            ..RunOptions::default()
+        };
+        run_local(&folder, &PackSource::FromFile(pack), &options)
+    }
+
+    #[test]
+    fn rejects_index_paths_outside_the_work_folder() {
+        for path in [
+            "../outside.jar",
+            "mods/../../outside.jar",
+            "/tmp/outside.jar",
+            "",
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let pack = write_pack(dir.path(), &[path], &[]);
+            assert!(
+                matches!(install(pack), Err(Error::UnsafePath { .. })),
+                "{path:?} was accepted"
+            );
+            assert!(!dir.path().join("outside.jar").exists());
+        }
+    }
+
+    #[test]
+    fn rejects_overrides_outside_the_work_folder() {
+        for name in [
+            "overrides/../outside.txt",
+            "server-overrides/../../outside.txt",
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let pack = write_pack(dir.path(), &[], &[name]);
+            assert!(
+                matches!(install(pack), Err(Error::UnsafePath { .. })),
+                "{name:?} was accepted"
+            );
+            assert!(!dir.path().join("outside.txt").exists());
+            assert!(!dir.path().join("server/outside.txt").exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_writing_through_symlinks_out_of_the_work_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(dir.path().join("server")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.path().join("server/mods")).unwrap();
+        let pack = write_pack(dir.path(), &[], &["overrides/mods/evil.jar"]);
+        assert!(matches!(install(pack), Err(Error::UnsafePath { .. })));
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    /// Answers one HTTP request with `body` and returns the URL to request.
+    fn serve_once(body: &'static [u8]) -> String {
+        use std::{io::BufRead, net::TcpListener};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a.jar", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = &stream;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn falls_back_to_the_next_mirror_and_records_it() {
+        let contents = b"mod jar";
+        let mirror = serve_once(contents);
+        let dir = tempfile::tempdir().unwrap();
+        let file = serde_json::json!({
+            "path": "mods/a.jar",
+            "hashes": {
+                "sha1": hex::encode(Sha1::digest(contents)),
+                "sha512": hex::encode(Sha512::digest(contents)),
+            },
+            "downloads": ["http://127.0.0.1:1/a.jar", mirror],
+            "fileSize": contents.len(),
+        });
+        let pack = write_index(dir.path(), vec![file], &[]);
+        install(pack).unwrap();
+        let server = dir.path().join("server");
+        assert_eq!(fs::read(server.join("mods/a.jar")).unwrap(), contents);
+        let config = crate::config::load_config(&server).unwrap();
+        assert_eq!(
+            config.files[Path::new("mods/a.jar")].source.as_deref(),
+            Some(mirror.as_str())
+        );
+    }
+
+    #[test]
+    fn aborts_on_a_download_that_does_not_match_its_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("server");
+        installed_before(&server, &[("mods/a.jar", false)], &[]);
+        let before = fs::read(server.join(CONFIG_NAME)).unwrap();
+        let good = b"good jar";
+        let file = serde_json::json!({
+            "path": "mods/a.jar",
+            "hashes": {
+                "sha1": hex::encode(Sha1::digest(good)),
+                "sha512": hex::encode(Sha512::digest(good)),
+            },
+            "downloads": [serve_once(b"evil jar")],
+        });
+        let pack = write_index(dir.path(), vec![file], &["overrides/config/mod.toml"]);
+        match install(pack) {
+            Err(Error::HashMismatch { path }) => assert_eq!(path, Path::new("mods/a.jar")),
+            other => panic!("expected a hash mismatch, got {other:?}"),
+        }
+        assert_eq!(
+            fs::read_to_string(server.join("mods/a.jar")).unwrap(),
+            "mods/a.jar"
+        );
+        assert!(!server.join("config/mod.toml").exists());
+        assert_eq!(fs::read(server.join(CONFIG_NAME)).unwrap(), before);
+    }
+
+    #[test]
+    fn keeps_the_loader_files_when_the_loader_is_not_reinstalled() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("server");
+        installed_before(
+            &server,
+            &[
+                ("run.sh", true),
+                ("libraries/loader.jar", true),
+                ("mods/old.jar", false),
+            ],
+            &[],
+        );
+        let pack = write_pack(dir.path(), &[], &["overrides/config/mod.toml"]);
+        let diff = install(pack).unwrap();
+        assert_eq!(diff.removed, [PathBuf::from("mods/old.jar")]);
+        assert!(!server.join("mods/old.jar").exists());
+        let config = crate::config::load_config(&server).unwrap();
+        for path in ["run.sh", "libraries/loader.jar"] {
+            assert_eq!(fs::read_to_string(server.join(path)).unwrap(), path);
+            assert!(
+                config.files[Path::new(path)].loader,
+                "{path} lost its record"
+            );
+        }
+        assert!(!config.files[Path::new("config/mod.toml")].loader);
+    }
+
+    #[test]
+    fn refuses_to_install_a_loader_over_unmanaged_files() {
+        let loader = InstalledLoader {
+            name: "fabric-loader".to_string(),
+            version: "0.15.0".to_string(),
+            minecraft_version: "1.20.1".to_string(),
+        };
+        let launcher = PathBuf::from("fabric-server-launch.jar");
+        let record = FileRecord {
+            sha512: Some("0".repeat(128)),
+            loader: true,
+            ..FileRecord::default()
+        };
+        let loader_files = BTreeMap::from([
+            (launcher, record.clone()),
+            (PathBuf::from("run.sh"), record),
+        ]);
+        let previous = UpdaterConfig {
+            protected: vec![PathBuf::from("run.sh")],
+            ..UpdaterConfig::with_files(BTreeMap::new())
+        };
+        let mut hashes = BTreeMap::new();
+        expect_loader_files(&mut hashes, &mut loader_files.clone());
+        let planned = |policy| {
+            let mut diff = PackDiff::between(Some(&previous), &hashes);
+            diff.find_collisions(|_| true);
+            diff.apply_collision_policy(policy);
+            check_loader_not_skipped(&loader, &diff, &loader_files)
+        };
+        match planned(CollisionPolicy::Skip) {
+            Err(Error::LoaderFilesInTheWay { paths, .. }) => {
+                assert_eq!(paths, [PathBuf::from("fabric-server-launch.jar")])
+            }
+            other => panic!("expected the launcher to be in the way, got {other:?}"),
+        }
+        planned(CollisionPolicy::Overwrite).unwrap();
+        planned(CollisionPolicy::Backup).unwrap();
+    }
+
+    #[test]
+    fn leaves_protected_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("server");
+        installed_before(
+            &server,
+            &[("config/mod.toml", false), ("config/dropped.toml", false)],
+            &["config/mod.toml", "config/dropped.toml"],
+        );
+        let pack = write_pack(dir.path(), &[], &["overrides/config/mod.toml"]);
+        let diff = install(pack).unwrap();
+        assert!(diff.removed.is_empty());
+        for path in ["config/mod.toml", "config/dropped.toml"] {
+            assert_eq!(fs::read_to_string(server.join(path)).unwrap(), path);
+        }
+        let config = crate::config::load_config(&server).unwrap();
+        assert_eq!(config.protected.len(), 2);
+    }
+
+    #[test]
+    fn skips_unmanaged_files_in_the_way() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("server");
+        fs::create_dir_all(server.join("config")).unwrap();
+        fs::write(server.join("config/mod.toml"), "by hand").unwrap();
+        let pack = write_pack(dir.path(), &[], &["overrides/config/mod.toml"]);
+        let diff = install(pack).unwrap();
+        assert_eq!(diff.collisions, [PathBuf::from("config/mod.toml")]);
+        assert_eq!(diff.skipped, [PathBuf::from("config/mod.toml")]);
+        assert_eq!(
+            fs::read_to_string(server.join("config/mod.toml")).unwrap(),
+            "by hand"
+        );
+        let config = crate::config::load_config(&server).unwrap();
+        assert!(!config.files.contains_key(Path::new("config/mod.toml")));
+    }
+
+    #[test]
+    fn accepts_ordinary_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = write_pack(dir.path(), &[], &["overrides/config/./mod.toml"]);
+        let diff = install(pack).unwrap();
+        assert_eq!(diff.added.len(), 1);
+        assert!(dir.path().join("server/config/mod.toml").exists());
+    }
 }
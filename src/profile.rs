@@ -0,0 +1,108 @@
+use std::{env, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, IoContext, Result},
+    AuthMethod, Side,
+};
+
+const PROFILES_NAME: &str = "profiles.json";
+
+/// `NMU_CONFIG_DIR` if set, otherwise `nmu` in the platform's config folder.
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("NMU_CONFIG_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    #[cfg(windows)]
+    let base = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    base.map(|base| base.join("nmu"))
+}
+/// A saved server: where it lives, which pack it runs and for which side.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Profile {
+    pub(crate) name: String,
+    pub(crate) target: ProfileTarget,
+    /// Path to a .mrpack file or URL, as typed by the user.
+    #[serde(default)]
+    pub(crate) pack_source: Option<String>,
+    pub(crate) side: Side,
+}
+/// Where a profile's work folder is. Passwords and key passphrases are never saved here.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProfileTarget {
+    Local {
+        folder: PathBuf,
+    },
+    Sftp {
+        address: String,
+        port: u32,
+        name: String,
+        auth: AuthMethod,
+        #[serde(default)]
+        key_file: Option<PathBuf>,
+    },
+}
+/// All saved profiles, kept in `profiles.json` in the [config folder](config_dir).
+#[derive(Serialize, Deserialize, Default)]
+pub struct ProfileStore {
+    pub(crate) profiles: Vec<Profile>,
+    /// Profile the window opens with.
+    #[serde(default)]
+    pub(crate) last_used: Option<String>,
+}
+impl ProfileStore {
+    fn path() -> Result<PathBuf> {
+        config_dir()
+            .map(|dir| dir.join(PROFILES_NAME))
+            .ok_or(Error::NoConfigDir)
+    }
+    /// Loads the saved profiles. Having none saved yet is not an error.
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&path).at(&path)?;
+        serde_json::from_str(&contents).map_err(|source| Error::Json { path, source })
+    }
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).at(parent)?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(|source| Error::Json {
+            path: path.clone(),
+            source,
+        })?;
+        fs::write(&path, contents).at(path)
+    }
+    pub fn get(&self, name: &str) -> Result<&Profile> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| Error::NoProfile(name.to_string()))
+    }
+    /// Adds the profile, replacing a saved one with the same name.
+    pub fn upsert(&mut self, profile: Profile) {
+        match self
+            .profiles
+            .iter_mut()
+            .find(|saved| saved.name == profile.name)
+        {
+            Some(saved) => *saved = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+    pub fn remove(&mut self, name: &str) {
+        self.profiles.retain(|profile| profile.name != name);
+        if self.last_used.as_deref() == Some(name) {
+            self.last_used = None;
+        }
+    }
+}
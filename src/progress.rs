@@ -0,0 +1,102 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::error::{Error, Result};
+
+/// Something that happened while downloading the files of a pack.
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    /// Downloads are about to start. `total_bytes` only counts files whose size is known.
+    Started { files: usize, total_bytes: u64 },
+    /// `downloaded` of the `size` bytes of a file have been written so far.
+    File {
+        path: PathBuf,
+        downloaded: u64,
+        size: u64,
+    },
+    /// A file was downloaded and its hashes checked.
+    Finished { path: PathBuf },
+}
+/// Where progress events go. Reporting never fails; events are dropped when nobody listens.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>);
+impl Progress {
+    pub fn new(listener: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(listener)))
+    }
+    pub fn report(&self, event: ProgressEvent) {
+        if let Some(listener) = &self.0 {
+            listener(event);
+        }
+    }
+}
+/// Lets another thread stop a running update. The update notices at its next check and rolls back.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// Fails with [`Error::Cancelled`] once cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+/// Totals of the events received so far, for showing a progress bar.
+#[derive(Default)]
+pub struct DownloadProgress {
+    pub(crate) files: usize,
+    pub(crate) files_done: usize,
+    pub(crate) total_bytes: u64,
+    pub(crate) downloaded_bytes: u64,
+    /// Files currently downloading, with their downloaded and total bytes.
+    pub(crate) current: BTreeMap<PathBuf, (u64, u64)>,
+}
+impl DownloadProgress {
+    pub fn apply(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Started { files, total_bytes } => {
+                *self = Self {
+                    files,
+                    total_bytes,
+                    ..Self::default()
+                };
+            }
+            ProgressEvent::File {
+                path,
+                downloaded,
+                size,
+            } => {
+                let (previous, _) = self
+                    .current
+                    .insert(path, (downloaded, size))
+                    .unwrap_or((0, 0));
+                self.downloaded_bytes += downloaded.saturating_sub(previous);
+                self.total_bytes = self.total_bytes.max(self.downloaded_bytes);
+            }
+            ProgressEvent::Finished { path } => {
+                self.current.remove(&path);
+                self.files_done += 1;
+            }
+        }
+    }
+    /// Share of the bytes downloaded so far, between 0 and 1.
+    pub fn fraction(&self) -> f32 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.downloaded_bytes as f32 / self.total_bytes as f32
+    }
+}
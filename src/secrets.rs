@@ -0,0 +1,264 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, IoContext, Result},
+    profile::config_dir,
+};
+
+/// Service name that secrets are filed under in the OS keyring.
+const KEYRING_SERVICE: &str = "nexusrealms-modpack-updater";
+const SECRETS_NAME: &str = "secrets.json";
+/// Encrypted with the master key so a wrong passphrase is noticed before anything is read or
+/// overwritten.
+const VERIFIER: &[u8] = b"nmu";
+
+/// Keyring account a location's password is stored under.
+pub fn account(name: &str, address: &str, port: u32) -> String {
+    format!("{name}@{address}:{port}")
+}
+/// Where SFTP passwords are kept between runs.
+pub enum SecretStore {
+    /// The OS keyring: Secret Service on Linux, Keychain on macOS, Credential Manager on Windows.
+    Keyring,
+    /// A file encrypted with a master passphrase, for systems without a reachable keyring.
+    File(EncryptedFile),
+    /// Kept in memory only. Selected with `NMU_SECRET_BACKEND=memory`, for tests.
+    Memory(BTreeMap<String, String>),
+}
+impl SecretStore {
+    /// The store picked by `NMU_SECRET_BACKEND` (`keyring`, `file` or `memory`), or the OS keyring
+    /// if it is reachable. `None` means the encrypted file has to be [opened](SecretStore::file).
+    pub fn without_passphrase() -> Option<Self> {
+        match env::var("NMU_SECRET_BACKEND").as_deref() {
+            Ok("memory") => Some(Self::Memory(BTreeMap::new())),
+            Ok("file") => None,
+            _ => keyring_reachable().then_some(Self::Keyring),
+        }
+    }
+    /// The encrypted file in the config folder, created on first use.
+    pub fn file(passphrase: &str) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(Error::EmptyPassphrase);
+        }
+        let path = secrets_path().ok_or(Error::NoConfigDir)?;
+        Ok(Self::File(EncryptedFile::open(path, passphrase)?))
+    }
+    /// Whether any secrets were saved to the encrypted file yet.
+    pub fn file_exists() -> bool {
+        secrets_path().is_some_and(|path| path.exists())
+    }
+    pub fn get(&self, account: &str) -> Result<Option<String>> {
+        match self {
+            Self::Keyring => match keyring_entry(account)?.get_password() {
+                Ok(secret) => Ok(Some(secret)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(err) => Err(Error::Keyring(err)),
+            },
+            Self::File(file) => file.get(account),
+            Self::Memory(secrets) => Ok(secrets.get(account).cloned()),
+        }
+    }
+    pub fn set(&mut self, account: &str, secret: &str) -> Result<()> {
+        match self {
+            Self::Keyring => keyring_entry(account)?
+                .set_password(secret)
+                .map_err(Error::Keyring),
+            Self::File(file) => file.set(account, secret),
+            Self::Memory(secrets) => {
+                secrets.insert(account.to_string(), secret.to_string());
+                Ok(())
+            }
+        }
+    }
+    /// Forgets a secret. Forgetting one that was never stored is not an error.
+    pub fn delete(&mut self, account: &str) -> Result<()> {
+        match self {
+            Self::Keyring => match keyring_entry(account)?.delete_credential() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(err) => Err(Error::Keyring(err)),
+            },
+            Self::File(file) => file.delete(account),
+            Self::Memory(secrets) => {
+                secrets.remove(account);
+                Ok(())
+            }
+        }
+    }
+}
+fn secrets_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SECRETS_NAME))
+}
+fn keyring_entry(account: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, account).map_err(Error::Keyring)
+}
+/// Looks up a secret that is never stored to see whether the keyring answers at all.
+fn keyring_reachable() -> bool {
+    keyring_entry("probe")
+        .is_ok_and(|entry| matches!(entry.get_password(), Ok(_) | Err(keyring::Error::NoEntry)))
+}
+/// Secrets encrypted with AES-256-GCM under a key derived from the master passphrase with Argon2id.
+pub struct EncryptedFile {
+    path: PathBuf,
+    key: Key<Aes256Gcm>,
+    contents: SecretsFile,
+}
+#[derive(Serialize, Deserialize)]
+struct SecretsFile {
+    /// Argon2 salt, hex encoded.
+    salt: String,
+    verifier: Sealed,
+    secrets: BTreeMap<String, Sealed>,
+}
+/// An encrypted value with its nonce, both hex encoded.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+impl EncryptedFile {
+    /// Opens the file at `path`, or starts a new one there if it does not exist yet.
+    pub fn open(path: PathBuf, passphrase: &str) -> Result<Self> {
+        if !path.exists() {
+            let mut salt = [0; 16];
+            OsRng.fill_bytes(&mut salt);
+            let key = derive_key(passphrase, &salt)?;
+            let contents = SecretsFile {
+                salt: hex::encode(salt),
+                verifier: seal(&key, VERIFIER)?,
+                secrets: BTreeMap::new(),
+            };
+            return Ok(Self {
+                path,
+                key,
+                contents,
+            });
+        }
+        let json = fs::read_to_string(&path).at(&path)?;
+        let contents: SecretsFile = serde_json::from_str(&json).map_err(|source| Error::Json {
+            path: path.clone(),
+            source,
+        })?;
+        let salt = hex::decode(&contents.salt).map_err(|_| corrupt(&path))?;
+        let key = derive_key(passphrase, &salt)?;
+        if open_sealed(&key, &contents.verifier).as_deref() != Some(VERIFIER) {
+            return Err(Error::WrongPassphrase);
+        }
+        Ok(Self {
+            path,
+            key,
+            contents,
+        })
+    }
+    fn get(&self, account: &str) -> Result<Option<String>> {
+        let Some(sealed) = self.contents.secrets.get(account) else {
+            return Ok(None);
+        };
+        let secret = open_sealed(&self.key, sealed).ok_or_else(|| corrupt(&self.path))?;
+        String::from_utf8(secret)
+            .map(Some)
+            .map_err(|_| corrupt(&self.path))
+    }
+    fn set(&mut self, account: &str, secret: &str) -> Result<()> {
+        let sealed = seal(&self.key, secret.as_bytes())?;
+        self.contents.secrets.insert(account.to_string(), sealed);
+        self.save()
+    }
+    fn delete(&mut self, account: &str) -> Result<()> {
+        if self.contents.secrets.remove(account).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).at(parent)?;
+        }
+        let json = serde_json::to_string_pretty(&self.contents).map_err(|source| Error::Json {
+            path: self.path.clone(),
+            source,
+        })?;
+        fs::write(&self.path, json).at(&self.path)
+    }
+}
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>> {
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| Error::SecretsCrypto)?;
+    Ok(key)
+}
+fn seal(key: &Key<Aes256Gcm>, plaintext: &[u8]) -> Result<Sealed> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(key)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| Error::SecretsCrypto)?;
+    Ok(Sealed {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+/// Decrypts a value, `None` if it was tampered with or sealed under another key.
+fn open_sealed(key: &Key<Aes256Gcm>, sealed: &Sealed) -> Option<Vec<u8>> {
+    let nonce = hex::decode(&sealed.nonce).ok()?;
+    let nonce = Nonce::from(<[u8; 12]>::try_from(nonce.as_slice()).ok()?);
+    let ciphertext = hex::decode(&sealed.ciphertext).ok()?;
+    Aes256Gcm::new(key)
+        .decrypt(&nonce, ciphertext.as_slice())
+        .ok()
+}
+fn corrupt(path: &Path) -> Error {
+    Error::SecretsCorrupt {
+        path: path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trips() {
+        let mut store = SecretStore::Memory(BTreeMap::new());
+        let account = account("mc", "example.com", 22);
+        assert_eq!(store.get(&account).unwrap(), None);
+        store.set(&account, "hunter2").unwrap();
+        assert_eq!(store.get(&account).unwrap().as_deref(), Some("hunter2"));
+        store.delete(&account).unwrap();
+        assert_eq!(store.get(&account).unwrap(), None);
+    }
+
+    #[test]
+    fn encrypted_file_needs_the_right_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SECRETS_NAME);
+        let mut file = EncryptedFile::open(path.clone(), "correct horse").unwrap();
+        file.set("mc@example.com:22", "hunter2").unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("hunter2"));
+
+        let reopened = EncryptedFile::open(path.clone(), "correct horse").unwrap();
+        assert_eq!(
+            reopened.get("mc@example.com:22").unwrap().as_deref(),
+            Some("hunter2")
+        );
+        assert!(matches!(
+            EncryptedFile::open(path, "wrong"),
+            Err(Error::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn refuses_an_empty_master_passphrase() {
+        assert!(matches!(SecretStore::file(""), Err(Error::EmptyPassphrase)));
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    fs,
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use russh_sftp::client::SftpSession;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error::{IoContext, Result, SftpContext},
+    log,
+};
+
+/// Folder inside the work folder where an update is prepared before it is swapped in.
+const STAGING_FOLDER: &str = ".nmu-staging";
+/// Staged files that will replace or add to the installed ones.
+const NEW_FOLDER: &str = "new";
+/// Installed files moved out of the way during the swap, kept until the update is committed.
+const OLD_FOLDER: &str = "old";
+/// Appended to the name of an unmanaged file that is kept when the pack replaces it.
+const BACKUP_SUFFIX: &str = ".nmu-backup";
+
+/// An update of a local work folder. Files are written into a staging folder first and only moved
+/// into place by [`Transaction::commit`], which restores the previous files if any move fails.
+pub struct Transaction {
+    folder: PathBuf,
+    staged: Vec<PathBuf>,
+    removals: Vec<PathBuf>,
+    backups: Vec<PathBuf>,
+}
+impl Transaction {
+    pub fn begin(folder: &Path) -> Result<Self> {
+        let staging = folder.join(STAGING_FOLDER);
+        if staging.exists() {
+            let old = staging.join(OLD_FOLDER);
+            if old.exists() {
+                log!("An earlier update was interrupted, putting its previous files back");
+                restore_moved_out(folder, &old, Path::new(""))?;
+            }
+            log!("Removing leftover staging folder from an earlier run");
+            fs::remove_dir_all(&staging).at(&staging)?;
+        }
+        fs::create_dir_all(staging.join(NEW_FOLDER)).at(staging.join(NEW_FOLDER))?;
+        Ok(Self {
+            folder: folder.to_path_buf(),
+            staged: Vec::new(),
+            removals: Vec::new(),
+            backups: Vec::new(),
+        })
+    }
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+    fn staging(&self) -> PathBuf {
+        self.folder.join(STAGING_FOLDER)
+    }
+    pub fn stage(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        let staged_path = self.staging_path(path)?;
+        fs::File::create(&staged_path)
+            .and_then(|mut file| file.write_all(contents))
+            .at(staged_path)?;
+        self.mark_staged(path);
+        Ok(())
+    }
+    /// Where `path` is staged, for writing it directly. Call [`Transaction::mark_staged`] once
+    /// the file is complete.
+    pub fn staging_path(&self, path: &Path) -> Result<PathBuf> {
+        create_parent_dirs(&self.staging().join(NEW_FOLDER), path)?;
+        Ok(self.staging().join(NEW_FOLDER).join(path))
+    }
+    pub fn mark_staged(&mut self, path: &Path) {
+        if !self.staged.iter().any(|staged| staged == path) {
+            self.staged.push(path.to_path_buf());
+        }
+    }
+    pub fn remove(&mut self, path: &Path) {
+        self.removals.push(path.to_path_buf());
+    }
+    /// Keeps the installed file at `path` under its [backup name](backup_path) instead of
+    /// discarding it when it is replaced.
+    pub fn back_up(&mut self, path: &Path) {
+        self.backups.push(path.to_path_buf());
+    }
+    /// Moves the staged files into place. On failure every file touched so far is put back.
+    pub fn commit(self) -> Result<()> {
+        let staging = self.staging();
+        let mut moved_out: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut placed: Vec<PathBuf> = Vec::new();
+        let result = (|| {
+            for path in self.removals.iter().chain(self.staged.iter()) {
+                if self.folder.join(path).exists() {
+                    let moved_to = if self.backups.contains(path) {
+                        self.folder.join(backup_path(path))
+                    } else {
+                        create_parent_dirs(&staging.join(OLD_FOLDER), path)?;
+                        staging.join(OLD_FOLDER).join(path)
+                    };
+                    fs::rename(self.folder.join(path), &moved_to).at(self.folder.join(path))?;
+                    moved_out.push((path.clone(), moved_to));
+                }
+            }
+            for path in &self.staged {
+                create_parent_dirs(&self.folder, path)?;
+                fs::rename(staging.join(NEW_FOLDER).join(path), self.folder.join(path))
+                    .at(self.folder.join(path))?;
+                placed.push(path.clone());
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            log!("Update failed, restoring previous files");
+            for path in &placed {
+                let _ = fs::remove_file(self.folder.join(path));
+            }
+            for (path, moved_to) in &moved_out {
+                let _ = fs::rename(moved_to, self.folder.join(path));
+            }
+            self.abort();
+            return Err(err);
+        }
+        let _ = fs::remove_dir_all(staging);
+        Ok(())
+    }
+    /// Throws away everything staged so far without touching the installed files.
+    pub fn abort(&self) {
+        let _ = fs::remove_dir_all(self.staging());
+    }
+}
+/// The same as [`Transaction`], for a work folder reached over SFTP.
+pub struct RemoteTransaction {
+    staged: Vec<PathBuf>,
+    removals: Vec<PathBuf>,
+    backups: Vec<PathBuf>,
+}
+impl RemoteTransaction {
+    pub async fn begin(sftp: &mut SftpSession) -> Result<Self> {
+        if sftp.try_exists(STAGING_FOLDER).await.unwrap_or(false) {
+            let old = Path::new(STAGING_FOLDER).join(OLD_FOLDER);
+            if sftp
+                .try_exists(old.to_string_lossy())
+                .await
+                .unwrap_or(false)
+            {
+                log!("An earlier update was interrupted, putting its previous files back");
+                restore_moved_out_remote(sftp, PathBuf::new()).await?;
+            }
+            log!("Removing leftover staging folder from an earlier run");
+            remove_dir_all_remote(sftp, STAGING_FOLDER.to_string()).await?;
+        }
+        let new_folder = Path::new(STAGING_FOLDER).join(NEW_FOLDER);
+        create_parent_dirs_remote(sftp, &new_folder.join("_")).await;
+        Ok(Self {
+            staged: Vec::new(),
+            removals: Vec::new(),
+            backups: Vec::new(),
+        })
+    }
+    pub async fn stage(
+        &mut self,
+        sftp: &mut SftpSession,
+        path: &Path,
+        contents: &[u8],
+    ) -> Result<()> {
+        let remote_path = self.staging_path(sftp, path).await;
+        let mut file = sftp.create(remote_path.clone()).await.at(&remote_path)?;
+        file.write_all(contents).await.at(&remote_path)?;
+        self.mark_staged(path);
+        Ok(())
+    }
+    /// Remote path where `path` is staged, for writing it directly. Call
+    /// [`RemoteTransaction::mark_staged`] once the file is complete.
+    pub async fn staging_path(&self, sftp: &SftpSession, path: &Path) -> String {
+        let staged_path = Path::new(STAGING_FOLDER).join(NEW_FOLDER).join(path);
+        create_parent_dirs_remote(sftp, &staged_path).await;
+        staged_path.to_string_lossy().to_string()
+    }
+    pub fn mark_staged(&mut self, path: &Path) {
+        if !self.staged.iter().any(|staged| staged == path) {
+            self.staged.push(path.to_path_buf());
+        }
+    }
+    pub fn remove(&mut self, path: &Path) {
+        self.removals.push(path.to_path_buf());
+    }
+    /// Keeps the installed file at `path` under its [backup name](backup_path) instead of
+    /// discarding it when it is replaced.
+    pub fn back_up(&mut self, path: &Path) {
+        self.backups.push(path.to_path_buf());
+    }
+    pub async fn commit(self, sftp: &mut SftpSession) -> Result<()> {
+        let old_folder = Path::new(STAGING_FOLDER).join(OLD_FOLDER);
+        let new_folder = Path::new(STAGING_FOLDER).join(NEW_FOLDER);
+        let mut moved_out: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut placed: Vec<PathBuf> = Vec::new();
+        let mut result = Ok(());
+        for path in self.removals.iter().chain(self.staged.iter()) {
+            if sftp
+                .try_exists(path.to_string_lossy())
+                .await
+                .unwrap_or(false)
+            {
+                let moved_to = if self.backups.contains(path) {
+                    backup_path(path)
+                } else {
+                    create_parent_dirs_remote(sftp, &old_folder.join(path)).await;
+                    old_folder.join(path)
+                };
+                if let Err(err) = sftp
+                    .rename(path.to_string_lossy(), moved_to.to_string_lossy())
+                    .await
+                {
+                    result = Err(err).at(path.to_string_lossy());
+                    break;
+                }
+                moved_out.push((path.clone(), moved_to));
+            }
+        }
+        if result.is_ok() {
+            for path in &self.staged {
+                create_parent_dirs_remote(sftp, path).await;
+                if let Err(err) = sftp
+                    .rename(
+                        new_folder.join(path).to_string_lossy(),
+                        path.to_string_lossy(),
+                    )
+                    .await
+                {
+                    result = Err(err).at(path.to_string_lossy());
+                    break;
+                }
+                placed.push(path.clone());
+            }
+        }
+        if let Err(err) = result {
+            log!("Update failed, restoring previous files");
+            for path in &placed {
+                let _ = sftp.remove_file(path.to_string_lossy()).await;
+            }
+            for (path, moved_to) in &moved_out {
+                let _ = sftp
+                    .rename(moved_to.to_string_lossy(), path.to_string_lossy())
+                    .await;
+            }
+            self.abort(sftp).await;
+            return Err(err);
+        }
+        let _ = remove_dir_all_remote(sftp, STAGING_FOLDER.to_string()).await;
+        Ok(())
+    }
+    pub async fn abort(&self, sftp: &mut SftpSession) {
+        let _ = remove_dir_all_remote(sftp, STAGING_FOLDER.to_string()).await;
+    }
+}
+/// Moves the installed files an interrupted commit left in `old` back into `folder`, replacing
+/// whatever the commit had already put in their place.
+fn restore_moved_out(folder: &Path, old: &Path, relative: &Path) -> Result<()> {
+    let dir = old.join(relative);
+    for entry in fs::read_dir(&dir).at(&dir)? {
+        let entry = entry.at(&dir)?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type().at(entry.path())?.is_dir() {
+            restore_moved_out(folder, old, &path)?;
+        } else {
+            log!("Restoring {}", path.display());
+            create_parent_dirs(folder, &path)?;
+            fs::rename(entry.path(), folder.join(&path)).at(folder.join(&path))?;
+        }
+    }
+    Ok(())
+}
+/// The same as [`restore_moved_out`], for a work folder reached over SFTP.
+fn restore_moved_out_remote(
+    sftp: &SftpSession,
+    relative: PathBuf,
+) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async move {
+        let dir = Path::new(STAGING_FOLDER).join(OLD_FOLDER).join(&relative);
+        let dir = dir.to_string_lossy().to_string();
+        let entries = sftp.read_dir(dir.clone()).await.at(&dir)?;
+        for entry in entries {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = relative.join(&name);
+            if entry.file_type().is_dir() {
+                restore_moved_out_remote(sftp, path).await?;
+            } else {
+                log!("Restoring {}", path.display());
+                create_parent_dirs_remote(sftp, &path).await;
+                let installed = path.to_string_lossy().to_string();
+                // SFTP renames do not replace existing files.
+                let _ = sftp.remove_file(installed.clone()).await;
+                sftp.rename(format!("{dir}/{name}"), installed.clone())
+                    .await
+                    .at(installed)?;
+            }
+        }
+        Ok(())
+    })
+}
+/// Where an unmanaged file at `path` is kept when the pack replaces it.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(BACKUP_SUFFIX);
+    PathBuf::from(name)
+}
+pub(crate) fn create_parent_dirs(folder: &Path, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(folder.join(parent)).at(folder.join(parent))?;
+    }
+    Ok(())
+}
+pub(crate) async fn create_parent_dirs_remote(sftp: &SftpSession, path: &Path) {
+    if let Some(parent) = path.parent() {
+        let mut current = PathBuf::new();
+        for component in parent.components() {
+            current.push(component);
+            let dir = current.to_string_lossy().to_string();
+            if !sftp.try_exists(dir.clone()).await.unwrap_or(false) {
+                let _ = sftp.create_dir(dir).await;
+            }
+        }
+    }
+}
+pub(crate) fn remove_dir_all_remote(
+    sftp: &SftpSession,
+    dir: String,
+) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+    Box::pin(async move {
+        let entries = sftp.read_dir(dir.clone()).await.at(&dir)?;
+        for entry in entries {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = format!("{dir}/{name}");
+            if entry.file_type().is_dir() {
+                remove_dir_all_remote(sftp, path).await?;
+            } else {
+                sftp.remove_file(path.clone()).await.at(path)?;
+            }
+        }
+        sftp.remove_dir(dir.clone()).await.at(dir)
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puts_back_files_an_interrupted_commit_moved_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path();
+        let old = folder.join(STAGING_FOLDER).join(OLD_FOLDER);
+        fs::create_dir_all(old.join("mods")).unwrap();
+        fs::write(old.join("mods/a.jar"), "old a").unwrap();
+        fs::write(old.join("removed.txt"), "old removed").unwrap();
+        fs::create_dir_all(folder.join("mods")).unwrap();
+        fs::write(folder.join("mods/a.jar"), "new a").unwrap();
+
+        let transaction = Transaction::begin(folder).unwrap();
+        assert_eq!(
+            fs::read_to_string(folder.join("mods/a.jar")).unwrap(),
+            "old a"
+        );
+        assert_eq!(
+            fs::read_to_string(folder.join("removed.txt")).unwrap(),
+            "old removed"
+        );
+        assert!(!old.exists());
+        transaction.abort();
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::Display;
+
+use crate::{
+    config::UpdaterConfig,
+    error::{Error, Result},
+    mrpack::{etag, get_mrpack, http_client, FetchedPack},
+    PackSource,
+};
+
+/// Outcome of comparing the installed pack with the one at the pack source.
+pub struct UpdateCheck {
+    pub(crate) installed: Option<String>,
+    pub(crate) available: Option<String>,
+    pub(crate) update_available: bool,
+}
+impl Display for UpdateCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let installed = self.installed.as_deref().unwrap_or("unknown version");
+        let available = self.available.as_deref().unwrap_or("unknown version");
+        if self.update_available {
+            write!(f, "Update available: {installed} -> {available}")
+        } else {
+            write!(f, "Up to date ({installed})")
+        }
+    }
+}
+/// Falls back to the endpoint recorded in `updater.json` when no pack source is selected.
+pub fn update_source(source: &PackSource, previous: Option<&UpdaterConfig>) -> PackSource {
+    match (
+        source,
+        previous.and_then(|config| config.pack_endpoint.as_ref()),
+    ) {
+        (PackSource::None, Some(url)) => PackSource::Url(url.clone()),
+        _ => source.clone(),
+    }
+}
+/// Looks for a newer pack without installing anything. A matching ETag skips downloading the pack,
+/// otherwise the hash of its index is compared with the one recorded at the last install.
+pub async fn check_for_update(
+    source: &PackSource,
+    previous: Option<&UpdaterConfig>,
+) -> Result<UpdateCheck> {
+    let installed = previous.and_then(|config| config.pack_version.clone());
+    if let (PackSource::Url(url), Some(recorded)) = (
+        source,
+        previous.and_then(|config| config.pack_etag.as_ref()),
+    ) {
+        let response = http_client()?
+            .head(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|source| Error::Http {
+                url: url.clone(),
+                source,
+            })?;
+        if etag(&response).as_ref() == Some(recorded) {
+            return Ok(UpdateCheck {
+                available: installed.clone(),
+                installed,
+                update_available: false,
+            });
+        }
+    }
+    let FetchedPack {
+        pack, index_hash, ..
+    } = get_mrpack(source).await?;
+    let update_available = match previous {
+        None => true,
+        Some(UpdaterConfig {
+            index_hash: Some(recorded),
+            ..
+        }) => !recorded.eq_ignore_ascii_case(&index_hash),
+        // Installs from before index hashes were recorded can only be compared by version.
+        Some(config) => config.pack_version.is_none() || config.pack_version != pack.version_id,
+    };
+    Ok(UpdateCheck {
+        installed,
+        available: pack.version_id,
+        update_available,
+    })
+}
@@ -0,0 +1,72 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use eframe::egui;
+
+use crate::{
+    log,
+    progress::{Cancel, Progress, ProgressEvent},
+};
+
+/// What a background job tells the window.
+pub enum JobEvent<T> {
+    Log(String),
+    Progress(ProgressEvent),
+    /// The job ended, with what it returned.
+    Finished(T),
+}
+/// A job running on its own thread so the window keeps responding while it works.
+pub struct Worker<T> {
+    events: Receiver<JobEvent<T>>,
+    cancel: Cancel,
+}
+impl<T: From<String> + Send + 'static> Worker<T> {
+    /// Runs `job` on a new thread, handing it the progress reporter and cancel flag to pass on to
+    /// the update. What the job returns is handed back once it is done, a crash becomes a message.
+    pub fn spawn(
+        ctx: &egui::Context,
+        job: impl FnOnce(Progress, Cancel) -> T + Send + 'static,
+    ) -> Self {
+        let (sender, events) = mpsc::channel();
+        let cancel = Cancel::default();
+        let job_cancel = cancel.clone();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let notify = {
+                let ctx = ctx.clone();
+                move |sender: &Sender<JobEvent<T>>, event| {
+                    let _ = sender.send(event);
+                    ctx.request_repaint();
+                }
+            };
+            let log_sender = sender.clone();
+            let log_notify = notify.clone();
+            log::set_sink(Some(Box::new(move |line: &str| {
+                log_notify(&log_sender, JobEvent::Log(line.to_string()))
+            })));
+            let progress_sender = sender.clone();
+            let progress_notify = notify.clone();
+            let progress = Progress::new(move |event| {
+                progress_notify(&progress_sender, JobEvent::Progress(event))
+            });
+            let message = panic::catch_unwind(AssertUnwindSafe(|| job(progress, job_cancel)))
+                .unwrap_or_else(|_| T::from(String::from("The job crashed")));
+            log::set_sink(None);
+            notify(&sender, JobEvent::Finished(message));
+        });
+        Self { events, cancel }
+    }
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+    /// Events that arrived since the last call.
+    pub fn events(&self) -> impl Iterator<Item = JobEvent<T>> + '_ {
+        self.events.try_iter()
+    }
+}